@@ -4,14 +4,19 @@
 // Distributed under terms of the GNU GPLv3 license.
 //
 
+use async_trait::async_trait;
 use diqwest::WithDigestAuth;
+use futures::{stream, StreamExt};
 use log::{debug, error, info, warn};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, time::Instant};
 
-use crate::models::{Atlas, AtlasCluster};
+use crate::{models::{Atlas, AtlasCluster}, provider::{self, Action, Provider, Resource}, report::{Outcome, State, Status}};
 
 const ATLAS_URL: &str = "https://cloud.mongodb.com";
 const ATLAS_API_VERSION: &str = "v2";
+// Tag used by owners to keep their clusters out of cerdito stop
+const SKIP_TAG: &str = concat!(env!("CARGO_PKG_NAME"), ":skip");
 
 const USER_AGENT: &str = concat!(
     env!("CARGO_PKG_NAME"),
@@ -24,14 +29,37 @@ struct Pause {
     paused: bool
 }
 
+#[derive(Deserialize)]
+struct Tag {
+    key: String,
+    value: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClusterDescription {
+    paused: bool,
+    state_name: String,
+    #[serde(default)]
+    tags: Vec<Tag>
+}
+
+impl AtlasCluster {
+    pub fn id(&self) -> String {
+        format!("atlas/{}", self.name)
+    }
+}
+
 impl Atlas {
-    pub fn new(public_key: Option<String>, private_key: Option<String>, clusters: Option<Vec<AtlasCluster>>) -> Atlas {
+    pub fn new(public_key: Option<String>, private_key: Option<String>, concurrency: Option<usize>, clusters: Option<Vec<AtlasCluster>>) -> Atlas {
         debug!("Atlas public key: {:?}", public_key);
         debug!("Atlas private key: {:?}", private_key.as_ref().map(|k| "*".repeat(k.len())));
+        debug!("Atlas concurrency: {:?}", concurrency);
         debug!("Atlas clusters: {:?}", clusters);
         Atlas {
             public_key,
             private_key,
+            concurrency,
             clusters
         }
     }
@@ -54,66 +82,166 @@ impl Atlas {
         false
     }
 
-    pub async fn pause(self, order: bool) {
+    async fn get_cluster(&self, client: &reqwest::Client, cluster: &AtlasCluster) -> Result<ClusterDescription, diqwest::error::Error> {
+        // Get the cluster description with values of self (safe to unwrap since has already been checked)
+        let url = format!("{}/api/atlas/{}/groups/{}/clusters/{}", &ATLAS_URL, &ATLAS_API_VERSION, &cluster.group_id, &cluster.name);
+        let response = client.get(&url)
+            .header("accept", "application/vnd.atlas.2023-02-01+json")
+            .send_with_digest_auth(self.public_key.as_ref().unwrap(), self.private_key.as_ref().unwrap())
+            .await?;
+        // Extract description from response
+        Ok(response.error_for_status()?.json::<ClusterDescription>().await?)
+    }
+
+    async fn pending_clusters<'a>(&self, client: &reqwest::Client, action: Action, clusters: Vec<&'a AtlasCluster>, outcomes: &mut Vec<Outcome>) -> Vec<&'a AtlasCluster> {
+        // Get the descriptions concurrently and keep the clusters not tagged to opt out (on stop) and not already paused or running
+        let mut selected = Vec::new();
+        let requests: Vec<_> = clusters.into_iter().map(|cluster| async move { (cluster, Instant::now(), self.get_cluster(client, cluster).await) }).collect();
+        let mut descriptions = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+        while let Some((cluster, start, description)) = descriptions.next().await {
+            match description {
+                Ok(description) => if action == Action::Stop && provider::opted_out(description.tags.iter().find(|t| t.key == SKIP_TAG).map(|t| &t.value)) {
+                    let reason = format!("opted out with tag {}", SKIP_TAG);
+                    info!("Skipping Atlas cluster {}, {}", &cluster.name, &reason);
+                    outcomes.push(Outcome::skipped("atlas", "cluster", &cluster.group_id, &cluster.name, start, &reason).of(&cluster.id()));
+                } else if description.paused == (action == Action::Stop) {
+                    info!("Atlas cluster {} is already {}", &cluster.name, if description.paused { "paused" } else { "running" });
+                    outcomes.push(Outcome::new("atlas", "cluster", &cluster.group_id, &cluster.name, State::Skipped, start).of(&cluster.id()));
+                } else {
+                    selected.push(cluster);
+                },
+                Err(err) => {
+                    let message = format!("Unexpected response when trying to get cluster {}, {}", &cluster.name, &err);
+                    error!("{}", &message);
+                    outcomes.push(Outcome::failed("atlas", "cluster", &cluster.group_id, &cluster.name, start, &message).of(&cluster.id()));
+                }
+            }
+        }
+        selected
+    }
+}
+
+#[async_trait]
+impl Provider for Atlas {
+    fn name(&self) -> &'static str {
+        "atlas"
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        self.clusters.iter().flatten().map(|c| Resource::new(c.id(), "cluster", &c.group_id, &c.name, &c.depends_on, &c.groups)).collect()
+    }
+
+    async fn execute(&self, action: Action, dry_run: bool, selection: &HashSet<String>) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
         if self.has_configuration() {
-            let (pre_action, action, post_action) = match order {
-                true => ("Stopping", "stop", "stopped"),
-                false => ("Starting", "start", "started")
-            };
-            let mut error = false;
+            let (pre_action, post_action) = (action.gerund(), action.participle());
             debug!("Trying to {} all configured clusters", action);
             // Create a http client
             let client = reqwest::Client::builder().connection_verbose(true).user_agent(USER_AGENT).build().expect("Client::new()");
             // Get values from self (safe to unwrap since has already been checked)
-            let public_key = self.public_key.unwrap();
-            let private_key = self.private_key.unwrap();
-            for cluster in self.clusters.unwrap() {
-                info!("{} Atlas cluster {}", &pre_action, &cluster.name);
+            let public_key = self.public_key.as_ref().unwrap();
+            let private_key = self.private_key.as_ref().unwrap();
+            let clusters: Vec<&AtlasCluster> = self.clusters.iter().flatten().filter(|c| selection.contains(&c.id())).collect();
+            // On stop the clusters tagged to opt out are skipped, and so are the ones already in the target state (always known on dry run)
+            let clusters = if action == Action::Stop || dry_run {
+                self.pending_clusters(&client, action, clusters, &mut outcomes).await
+            } else {
+                clusters
+            };
+            if dry_run {
+                for cluster in clusters {
+                    outcomes.push(Outcome::planned("atlas", "cluster", &cluster.group_id, &cluster.name, &action.to_string()).of(&cluster.id()));
+                }
+                return outcomes;
+            }
+            // Perform the requests concurrently and process the responses in order
+            let client = &client;
+            let requests: Vec<_> = clusters.into_iter().map(|cluster| async move {
+                let start = Instant::now();
                 // Build URL
                 let url = format!("{}/api/atlas/{}/groups/{}/clusters/{}", &ATLAS_URL, &ATLAS_API_VERSION, &cluster.group_id, &cluster.name);
                 // Perform request
                 let response = client.patch(&url)
                     .header("accept", "application/vnd.atlas.2023-02-01+json")
-                    .json(&Pause{paused: order})
-                    .send_with_digest_auth(&public_key, &private_key)
+                    .json(&Pause{paused: action == Action::Stop})
+                    .send_with_digest_auth(public_key, private_key)
                     .await;
-                match response {
+                (cluster, start, response)
+            }).collect();
+            let mut responses = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+            while let Some((cluster, start, response)) = responses.next().await {
+                info!("{} Atlas cluster {}", &pre_action, &cluster.name);
+                let outcome = match response {
                     Ok(response) => {
                         if response.status().is_success() {
-                            debug!("Atlas cluster {} {}", &cluster.name, &post_action)
+                            debug!("Atlas cluster {} {}", &cluster.name, &post_action);
+                            Outcome::new("atlas", "cluster", &cluster.group_id, &cluster.name, State::Done, start).of(&cluster.id())
                         } else {
                             let status = response.status();
                             match response.text().await {
                                 Ok(text) => if text.contains("CLUSTER_ALREADY_PAUSED") {
                                     info!("Atlas cluster {} is already paused", &cluster.name);
+                                    Outcome::new("atlas", "cluster", &cluster.group_id, &cluster.name, State::Skipped, start).of(&cluster.id())
                                 } else {
                                     // Add text to empty text responses
-                                    let text = if &text == "" {
+                                    let text = if text.is_empty() {
                                         String::from("empty text response")
                                     } else {
                                         text
                                     };
-                                    error!("Bad response status code {} when trying to {} cluster {}, {}", &status, &action, &cluster.name, &text);
-                                    error = true;
+                                    let message = format!("Bad response status code {} when trying to {} cluster {}, {}", &status, &action, &cluster.name, &text);
+                                    error!("{}", &message);
+                                    Outcome::failed("atlas", "cluster", &cluster.group_id, &cluster.name, start, &message).of(&cluster.id())
                                 },
                                 Err(err) => {
-                                    error!("Bad response status code {} when trying to {} cluster {}, {}", &status, &action, &cluster.name, &err);
-                                    error = true;
+                                    let message = format!("Bad response status code {} when trying to {} cluster {}, {}", &status, &action, &cluster.name, &err);
+                                    error!("{}", &message);
+                                    Outcome::failed("atlas", "cluster", &cluster.group_id, &cluster.name, start, &message).of(&cluster.id())
                                 }
                             }
                         }
                     },
                     Err(err) => {
-                        error!("Unexpected response when trying to {} cluster {}, {}", &action, &cluster.name, &err);
-                        error = true;
+                        let message = format!("Unexpected response when trying to {} cluster {}, {}", &action, &cluster.name, &err);
+                        error!("{}", &message);
+                        Outcome::failed("atlas", "cluster", &cluster.group_id, &cluster.name, start, &message).of(&cluster.id())
                     }
-                }
+                };
+                outcomes.push(outcome);
             }
-            if error {
+            if outcomes.iter().any(|o| o.state == State::Failed) {
                 debug!("Some (or all) clusters have failed to {}", &action)
             } else {
                 debug!("All clusters have been {}", &post_action)
             }
         }
+        outcomes
+    }
+
+    async fn status(&self, selection: &HashSet<String>) -> Vec<Status> {
+        let mut statuses = Vec::new();
+        if self.has_configuration() {
+            // Create a http client
+            let client = reqwest::Client::builder().connection_verbose(true).user_agent(USER_AGENT).build().expect("Client::new()");
+            // Get the descriptions concurrently and process them in order
+            let client = &client;
+            let requests: Vec<_> = self.clusters.iter().flatten().filter(|c| selection.contains(&c.id())).map(|cluster| async move { (cluster, self.get_cluster(client, cluster).await) }).collect();
+            let mut descriptions = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+            while let Some((cluster, description)) = descriptions.next().await {
+                let state = match description {
+                    Ok(description) => if description.paused {
+                        String::from("paused")
+                    } else {
+                        description.state_name.to_lowercase()
+                    },
+                    Err(err) => {
+                        error!("Unexpected response when trying to get status of cluster {}, {}", &cluster.name, &err);
+                        String::from("unknown")
+                    }
+                };
+                statuses.push(Status::new("atlas", "cluster", &cluster.group_id, &cluster.name, &state));
+            }
+        }
+        statuses
     }
 }
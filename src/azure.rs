@@ -4,14 +4,19 @@
 // Distributed under terms of the GNU GPLv3 license.
 //
 
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::{collections::{HashMap, HashSet}, time::Instant};
 
-use crate::models::{Azure, AKS, Databricks};
+use crate::{models::{Azure, AKS, Databricks}, provider::{self, Action, Provider, Resource}, report::{Outcome, State, Status}};
 
 const AZURE_URL: &str = "https://management.azure.com";
 const AZURE_API_VERSION: &str = "2024-02-01";
 const AZURE_DATABRICKS_SCOPE: &str = "2ff814a6-3304-4ab8-85cb-cd0e6f879c1d";
+// Tag used by owners to keep their AKS out of cerdito stop
+const SKIP_TAG: &str = concat!(env!("CARGO_PKG_NAME"), ":skip");
 
 const USER_AGENT: &str = concat!(
     env!("CARGO_PKG_NAME"),
@@ -24,6 +29,24 @@ struct Token {
     access_token: String
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PowerState {
+    code: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManagedClusterProperties {
+    power_state: PowerState
+}
+
+#[derive(Deserialize)]
+struct ManagedCluster {
+    properties: ManagedClusterProperties,
+    tags: Option<HashMap<String, String>>
+}
+
 #[derive(Deserialize, Serialize)]
 struct DatabricksJobSchedule {
     quartz_cron_expression: String,
@@ -65,17 +88,62 @@ struct DatabricksDbfsDeleteRequest {
     recursive: bool
 }
 
+fn plan_databricks_jobs(databricks: &Databricks, jobs: DatabricksJobs, action: &str) -> Vec<DatabricksJob> {
+    let mut planned = Vec::new();
+    // Make list of configured jobs mutable to remove items
+    let mut databricks_jobs = databricks.jobs.clone();
+    for job in jobs.jobs {
+        // Determine if job must be paused / unpaused
+        let perform_action = if databricks.all_jobs && job.settings.schedule.is_some() {
+            true
+        } else if databricks_jobs.contains(&job.settings.name) {
+            // Remove job from list
+            databricks_jobs.retain(|j| *j != job.settings.name);
+            if job.settings.schedule.is_none() {
+                warn!("It is not possible to {} job {} in {} because it is not scheduled in Databricks", action, &job.settings.name, &databricks.url);
+                false
+            } else {
+                true
+            }
+        } else {
+            false
+        };
+        if perform_action {
+            planned.push(job);
+        }
+    }
+    // If any configured job remains warn about it
+    for job in databricks_jobs {
+        warn!("It is not possible to {} job {} in {} because it is not defined in Databricks", action, &job, &databricks.url)
+    }
+    planned
+}
+
+impl AKS {
+    pub fn id(&self) -> String {
+        format!("aks/{}", self.resource_name)
+    }
+}
+
+impl Databricks {
+    pub fn id(&self) -> String {
+        format!("databricks/{}", self.url)
+    }
+}
+
 impl Azure {
-    pub fn new(tenant_id: Option<String>, client_id: Option<String>, client_secret: Option<String>, aks: Option<Vec<AKS>>, databricks: Option<Vec<Databricks>>) -> Azure {
+    pub fn new(tenant_id: Option<String>, client_id: Option<String>, client_secret: Option<String>, concurrency: Option<usize>, aks: Option<Vec<AKS>>, databricks: Option<Vec<Databricks>>) -> Azure {
         debug!("Azure tenant ID: {:?}", tenant_id);
         debug!("Azure client ID: {:?}", client_id);
         debug!("Azure client secret: {:?}", client_secret.as_ref().map(|k| "*".repeat(k.len())));
+        debug!("Azure concurrency: {:?}", concurrency);
         debug!("AKS: {:?}", aks);
         debug!("Databricks: {:?}", databricks);
         Azure {
             tenant_id,
             client_id,
             client_secret,
+            concurrency,
             aks,
             databricks
         }
@@ -99,26 +167,6 @@ impl Azure {
         false
     }
 
-    fn has_aks_configuration(&self) -> bool {
-        // Check if has AKS configuration
-        if self.aks.is_none() {
-            info!("No AKS configured, skipping AKS action");
-            false
-        } else {
-            true
-        }
-    }
-
-    fn has_databricks_configuration(&self) -> bool {
-        // Check if has Databricks configuration
-        if self.databricks.is_none() {
-            info!("No Databricks configured, skipping Databricks action");
-            false
-        } else {
-            true
-        }
-    }
-
     async fn get_azure_token(&self, client: &reqwest::Client, scope: String) -> Result<String, reqwest::Error> {
         // Get the Azure token with values of self (safe to unwrap since has already been checked)
         let mut form_data = std::collections::HashMap::new();
@@ -135,7 +183,7 @@ impl Azure {
             .send()
             .await?;
         // Extract token from response
-        Ok(response.error_for_status()?.json::<Token>().await.map(|t| t.access_token)?)
+        response.error_for_status()?.json::<Token>().await.map(|t| t.access_token)
     }
 
     async fn get_databricks_jobs(&self, client: &reqwest::Client, token: &String, url: reqwest::Url) -> Result<DatabricksJobs, reqwest::Error> {
@@ -146,15 +194,53 @@ impl Azure {
             .send()
             .await?;
         // Extract list from response
-        Ok(response.error_for_status()?.json::<DatabricksJobs>().await?)
+        response.error_for_status()?.json::<DatabricksJobs>().await
     }
 
-    async fn pause_aks(&self, order: bool) {
-        if self.has_aks_configuration() {
-        let (pre_action, action, post_action) = match order {
-                true => ("Stopping", "stop", "stopped"),
-                false => ("Starting", "start", "started")
-            };
+    async fn get_aks(&self, client: &reqwest::Client, token: &String, aks: &AKS) -> Result<ManagedCluster, reqwest::Error> {
+        // Get AKS managed cluster
+        let url = format!("{AZURE_URL}/subscriptions/{}/resourceGroups/{}/providers/Microsoft.ContainerService/managedClusters/{}?api-version={AZURE_API_VERSION}", &aks.subscription_id, &aks.resource_group_name, &aks.resource_name);
+        let response = client.get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?;
+        // Extract managed cluster from response
+        response.error_for_status()?.json::<ManagedCluster>().await
+    }
+
+    async fn pending_aks<'a>(&self, client: &reqwest::Client, token: &String, action: Action, aks_list: Vec<&'a AKS>, outcomes: &mut Vec<Outcome>) -> (Vec<&'a AKS>, bool) {
+        // Get the managed clusters concurrently and keep those not tagged to opt out (on stop) and not already stopped or running
+        let mut error = false;
+        let mut selected = Vec::new();
+        let requests: Vec<_> = aks_list.into_iter().map(|aks| async move { (aks, Instant::now(), self.get_aks(client, token, aks).await) }).collect();
+        let mut clusters = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+        while let Some((aks, start, cluster)) = clusters.next().await {
+            match cluster {
+                Ok(cluster) => if action == Action::Stop && provider::opted_out(cluster.tags.as_ref().and_then(|t| t.get(SKIP_TAG))) {
+                    let reason = format!("opted out with tag {}", SKIP_TAG);
+                    info!("Skipping AKS {}, {}", &aks.resource_name, &reason);
+                    outcomes.push(Outcome::skipped("azure", "aks", &aks.resource_group_name, &aks.resource_name, start, &reason).of(&aks.id()));
+                } else if cluster.properties.power_state.code.eq_ignore_ascii_case(if action == Action::Stop { "Stopped" } else { "Running" }) {
+                    info!("AKS {} is already {}", &aks.resource_name, cluster.properties.power_state.code.to_lowercase());
+                    outcomes.push(Outcome::new("azure", "aks", &aks.resource_group_name, &aks.resource_name, State::Skipped, start).of(&aks.id()));
+                } else {
+                    selected.push(aks);
+                },
+                Err(err) => {
+                    let message = format!("Unexpected response when trying to get AKS {}, {}", &aks.resource_name, &err);
+                    error!("{}", &message);
+                    error = true;
+                    outcomes.push(Outcome::failed("azure", "aks", &aks.resource_group_name, &aks.resource_name, start, &message).of(&aks.id()));
+                }
+            }
+        }
+        (selected, error)
+    }
+
+    async fn pause_aks(&self, action: Action, dry_run: bool, selection: &HashSet<String>, outcomes: &mut Vec<Outcome>) {
+        let aks_list: Vec<&AKS> = self.aks.iter().flatten().filter(|a| selection.contains(&a.id())).collect();
+        if !aks_list.is_empty() {
+            let (pre_action, post_action) = (action.gerund(), action.participle());
             let mut error = false;
             debug!("Trying to {} all configured AKS", action);
             // Create a http client
@@ -162,8 +248,24 @@ impl Azure {
             let token = self.get_azure_token(&client, format!("{AZURE_URL}/.default")).await;
             match token {
                 Ok(token) => {
-                    for aks in self.aks.as_ref().unwrap() {
-                        info!("{} AKS {}", &pre_action, &aks.resource_name);
+                    let (client, token) = (&client, &token);
+                    // On stop the AKS tagged to opt out are skipped, and so are the ones already in the target state (always known on dry run)
+                    let aks_list = if action == Action::Stop || dry_run {
+                        let (aks_list, failed) = self.pending_aks(client, token, action, aks_list, outcomes).await;
+                        error = failed;
+                        aks_list
+                    } else {
+                        aks_list
+                    };
+                    if dry_run {
+                        for aks in &aks_list {
+                            outcomes.push(Outcome::planned("azure", "aks", &aks.resource_group_name, &aks.resource_name, &action.to_string()).of(&aks.id()));
+                        }
+                        return;
+                    }
+                    // Perform the requests concurrently and process the responses in order
+                    let requests: Vec<_> = aks_list.iter().map(|aks| async move {
+                        let start = Instant::now();
                         // Build action URL
                         let url = format!("{AZURE_URL}/subscriptions/{}/resourceGroups/{}/providers/Microsoft.ContainerService/managedClusters/{}/{}?api-version={AZURE_API_VERSION}", &aks.subscription_id, &aks.resource_group_name, &aks.resource_name, &action);
                         // Perform request
@@ -172,42 +274,54 @@ impl Azure {
                             .header("Content-Length", "0")
                             .send()
                             .await;
-                        match response {
+                        (aks, start, response)
+                    }).collect();
+                    let mut responses = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+                    while let Some((aks, start, response)) = responses.next().await {
+                        info!("{} AKS {}", &pre_action, &aks.resource_name);
+                        let result = match response {
                             Ok(response) => {
                                 if response.status().is_success() {
                                     debug!("AKS {} {}", &aks.resource_name, &post_action);
+                                    Ok(State::Done)
                                 } else {
                                     let status = response.status();
                                     match response.text().await {
-                                        Ok(text) => if order && text.contains("is not currently running") {
+                                        Ok(text) => if action == Action::Stop && text.contains("is not currently running") {
                                             info!("AKS {} is already paused", &aks.resource_name);
+                                            Ok(State::Skipped)
                                         } else {
                                             // Add text to empty text responses
-                                            let text = if &text == "" {
+                                            let text = if text.is_empty() {
                                                 String::from("empty text response")
                                             } else {
                                                 text
                                             };
-                                            error!("Bad response status code {} when trying to {} AKS {}, {}", &status, &action, &aks.resource_name, &text);
-                                            error = true;
-                                        },
-                                        Err(err) => {
-                                            error!("Bad response status code {} when trying to {} AKS {}, {}", &status, &action, &aks.resource_name, &err);
-                                            error = true;
+                                            Err(format!("Bad response status code {} when trying to {} AKS {}, {}", &status, &action, &aks.resource_name, &text))
                                         },
+                                        Err(err) => Err(format!("Bad response status code {} when trying to {} AKS {}, {}", &status, &action, &aks.resource_name, &err))
                                     }
                                 }
                             },
-                            Err(err) => {
-                                error!("Unexpected response when trying to {} AKS {}, {}", &action, &aks.resource_name, &err);
+                            Err(err) => Err(format!("Unexpected response when trying to {} AKS {}, {}", &action, &aks.resource_name, &err))
+                        };
+                        match result {
+                            Ok(state) => outcomes.push(Outcome::new("azure", "aks", &aks.resource_group_name, &aks.resource_name, state, start).of(&aks.id())),
+                            Err(message) => {
+                                error!("{}", &message);
                                 error = true;
+                                outcomes.push(Outcome::failed("azure", "aks", &aks.resource_group_name, &aks.resource_name, start, &message).of(&aks.id()));
                             }
                         }
                     }
                 },
                 Err(err) => {
-                    error!("Unexpected response when trying to obtain Azure token, {}", &err);
+                    let message = format!("Unexpected response when trying to obtain Azure token, {}", &err);
+                    error!("{}", &message);
                     error = true;
+                    for aks in &aks_list {
+                        outcomes.push(Outcome::failed("azure", "aks", &aks.resource_group_name, &aks.resource_name, Instant::now(), &message).of(&aks.id()));
+                    }
                 }
             }
             if error {
@@ -218,11 +332,13 @@ impl Azure {
         }
     }
 
-    async fn pause_databricks(&self, order: bool) {
-        if self.has_databricks_configuration() {
-            let (pre_action, action, post_action, pause_status) = match order {
-                true => ("Stopping", "stop", "stopped", "PAUSED"),
-                false => ("Starting", "start", "started", "UNPAUSED")
+    async fn pause_databricks(&self, action: Action, dry_run: bool, selection: &HashSet<String>, stopped: Option<&HashMap<String, Vec<String>>>, outcomes: &mut Vec<Outcome>) {
+        let databricks_list: Vec<&Databricks> = self.databricks.iter().flatten().filter(|d| selection.contains(&d.id())).collect();
+        if !databricks_list.is_empty() {
+            let (pre_action, post_action) = (action.gerund(), action.participle());
+            let pause_status = match action {
+                Action::Stop => "PAUSED",
+                Action::Start => "UNPAUSED"
             };
             let mut error = false;
             debug!("Trying to {} all configured Databricks", action);
@@ -231,7 +347,8 @@ impl Azure {
             let token = self.get_azure_token(&client, format!("{AZURE_DATABRICKS_SCOPE}/.default")).await;
             match token {
                 Ok(token) => {
-                    for databricks in self.databricks.as_ref().unwrap() {
+                    for databricks in &databricks_list {
+                        let start = Instant::now();
                         info!("{} Databricks jobs in {}", &pre_action, &databricks.url);
                         // Build URL (Calling unwrap is safe in join because path is valid)
                         let url = reqwest::Url::parse(&databricks.url).map(|u| u.join("/api/2.1/jobs/list").unwrap());
@@ -240,26 +357,39 @@ impl Azure {
                                 // Get Databricks jobs list to extract ID and schedule
                                 match self.get_databricks_jobs(&client, &token, url).await {
                                     Ok(jobs) => {
-                                        // Make list of configured jobs mutable to remove items
-                                        let mut databricks_jobs = databricks.jobs.clone();
-                                        for job in jobs.jobs {
-                                            // Determine if job must be paused / unpaused
-                                            let perform_action = if databricks.all_jobs && job.settings.schedule.is_some() {
-                                                true
-                                            } else if databricks_jobs.contains(&job.settings.name) {
-                                                // Remove job from list
-                                                databricks_jobs.retain(|j| *j != job.settings.name);
-                                                if job.settings.schedule.is_none() {
-                                                    warn!("It is not possible to {} job {} in {} because it is not scheduled in Databricks", &action, &job.settings.name, &databricks.url);
-                                                    false
-                                                } else {
-                                                    true
+                                        let mut jobs = plan_databricks_jobs(databricks, jobs, &action.to_string());
+                                        // When asked, only the jobs paused by cerdito are unpaused
+                                        if let Some(stopped) = stopped {
+                                            let recorded = stopped.get(&databricks.id()).map(Vec::as_slice).unwrap_or_default();
+                                            jobs.retain(|job| {
+                                                let paused = recorded.contains(&job.job_id.to_string());
+                                                if !paused {
+                                                    let reason = "not previously stopped by cerdito";
+                                                    info!("Skipping job {} in {}, {}", &job.settings.name, &databricks.url, reason);
+                                                    outcomes.push(Outcome::skipped("azure", "databricks job", &databricks.url, &job.settings.name, Instant::now(), reason).of(&databricks.id()).item(&job.job_id.to_string()));
                                                 }
-                                            } else {
-                                                false
-                                            };
-                                            if perform_action {
-                                                // Get schedule (Safe unwrap since is checked before) and change pause status
+                                                paused
+                                            });
+                                        }
+                                        // Jobs already paused (or unpaused) are skipped (safe unwrap since schedule is checked in plan)
+                                        jobs.retain(|job| {
+                                            let done = job.settings.schedule.as_ref().unwrap().pause_status == pause_status;
+                                            if done {
+                                                info!("Job {} in {} is already {}", &job.settings.name, &databricks.url, pause_status.to_lowercase());
+                                                outcomes.push(Outcome::new("azure", "databricks job", &databricks.url, &job.settings.name, State::Skipped, Instant::now()).of(&databricks.id()).item(&job.job_id.to_string()));
+                                            }
+                                            !done
+                                        });
+                                        if dry_run {
+                                            for job in jobs {
+                                                outcomes.push(Outcome::planned("azure", "databricks job", &databricks.url, &job.settings.name, &action.to_string()).of(&databricks.id()).item(&job.job_id.to_string()));
+                                            }
+                                        } else {
+                                            // Perform the updates concurrently and process the responses in order
+                                            let (client, token) = (&client, &token);
+                                            let requests: Vec<_> = jobs.into_iter().map(|job| async move {
+                                                let start = Instant::now();
+                                                // Get schedule (Safe unwrap since is checked in plan) and change pause status
                                                 let mut schedule = job.settings.schedule.unwrap();
                                                 schedule.pause_status = pause_status.to_string();
                                                 // Build URL (Safe unwrap since is checked before and path is valid)
@@ -268,7 +398,7 @@ impl Azure {
                                                 let json = DatabricksJobUpdateRequest {
                                                     job_id: job.job_id,
                                                     new_settings: DatabricksJobUpdate {
-                                                        schedule: schedule
+                                                        schedule
                                                     }
                                                 };
                                                 let response = client.post(url)
@@ -276,46 +406,61 @@ impl Azure {
                                                     .json(&json)
                                                     .send()
                                                     .await;
-                                                match response {
+                                                (job.job_id.to_string(), job.settings.name, start, response)
+                                            }).collect();
+                                            let mut responses = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+                                            while let Some((id, name, start, response)) = responses.next().await {
+                                                let result = match response {
                                                     Ok(response) => {
                                                         if response.status().is_success() {
-                                                             info!("Job {} in {} {}", &job.settings.name, &databricks.url, &post_action);
+                                                            info!("Job {} in {} {}", &name, &databricks.url, &post_action);
+                                                            Ok(())
                                                         } else {
-                                                            error!("Bad response status code {} when trying to {} job {} in {}", response.status(), &action, &job.settings.name, &databricks.url);
-                                                            error = true;
+                                                            Err(format!("Bad response status code {} when trying to {} job {} in {}", response.status(), &action, &name, &databricks.url))
                                                         }
                                                     },
-                                                    Err(err) => {
-                                                        error!("Unexpected response when trying to {} job {} in {}, {}", &action, &job.settings.name, &databricks.url, &err);
+                                                    Err(err) => Err(format!("Unexpected response when trying to {} job {} in {}, {}", &action, &name, &databricks.url, &err))
+                                                };
+                                                match result {
+                                                    Ok(_) => outcomes.push(Outcome::new("azure", "databricks job", &databricks.url, &name, State::Done, start).of(&databricks.id()).item(&id)),
+                                                    Err(message) => {
+                                                        error!("{}", &message);
                                                         error = true;
+                                                        outcomes.push(Outcome::failed("azure", "databricks job", &databricks.url, &name, start, &message).of(&databricks.id()).item(&id));
                                                     }
                                                 }
                                             }
                                         }
-                                        // If any configured job remains warn about it
-                                        for job in databricks_jobs {
-                                            warn!("It is not possible to {} job {} in {} because it is not defined in Databricks", &action, &job, &databricks.url)
-                                        }
                                     },
                                     Err(err) => {
-                                        error!("Error when trying to get Databricks jobs list, {}", &err);
+                                        let message = format!("Error when trying to get Databricks jobs list, {}", &err);
+                                        error!("{}", &message);
                                         error = true;
+                                        outcomes.push(Outcome::failed("azure", "databricks", &databricks.url, "jobs", start, &message).of(&databricks.id()));
                                     }
                                 }
                             },
                             Err(err) => {
-                                error!("Unexpected error when trying to parse Databricks URL, {}", &err);
+                                let message = format!("Unexpected error when trying to parse Databricks URL, {}", &err);
+                                error!("{}", &message);
                                 error = true;
+                                outcomes.push(Outcome::failed("azure", "databricks", &databricks.url, "jobs", start, &message).of(&databricks.id()));
+                                continue;
                             }
                         }
-                        // On stop seek for delete config and try to delete
-                        if order && databricks.delete.is_some() {
+                        // On stop seek for delete config and try to delete, deletions are cleanup and
+                        // they do not belong to the resource so it is not recorded as stopped for them
+                        if let (Action::Stop, Some(delete_list)) = (action, databricks.delete.as_ref()) {
                             let mut delete_error = false;
-                            let delete_list = databricks.delete.as_ref().unwrap();
                             info!("Deleting Databricks files/directories {:?} in {}", delete_list, &databricks.url);
                             // Build URL (Calling unwrap is safe in join because path is valid and url was checked before)
                             let url = reqwest::Url::parse(&databricks.url).map(|u| u.join("/api/2.0/dbfs/delete").unwrap()).unwrap();
                             for delete in delete_list {
+                                let start = Instant::now();
+                                if dry_run {
+                                    outcomes.push(Outcome::planned("azure", "databricks file", &databricks.url, delete, "delete"));
+                                    continue;
+                                }
                                 // Request delete
                                 let json = DatabricksDbfsDeleteRequest {
                                     path: delete.into(),
@@ -326,18 +471,23 @@ impl Azure {
                                     .json(&json)
                                     .send()
                                     .await;
-                                match response {
+                                let result = match response {
                                     Ok(response) => {
                                         if response.status().is_success() {
                                             info!("File/directory {} in {} deleted", delete, &databricks.url);
+                                            Ok(())
                                         } else {
-                                            error!("Bad response status code {} when trying to delete file/directory {} in {}", response.status(), delete, &databricks.url);
-                                            delete_error = true;
+                                            Err(format!("Bad response status code {} when trying to delete file/directory {} in {}", response.status(), delete, &databricks.url))
                                         }
                                     },
-                                    Err(err) => {
-                                        error!("Unexpected response when trying to delete file/directory {} in {}, {}", delete, &databricks.url, &err);
+                                    Err(err) => Err(format!("Unexpected response when trying to delete file/directory {} in {}, {}", delete, &databricks.url, &err))
+                                };
+                                match result {
+                                    Ok(_) => outcomes.push(Outcome::new("azure", "databricks file", &databricks.url, delete, State::Done, start)),
+                                    Err(message) => {
+                                        error!("{}", &message);
                                         delete_error = true;
+                                        outcomes.push(Outcome::failed("azure", "databricks file", &databricks.url, delete, start, &message));
                                     }
                                 }
                             }
@@ -350,8 +500,12 @@ impl Azure {
                     }
                 },
                 Err(err) => {
-                    error!("Unexpected response when trying to obtain Azure token, {}", &err);
+                    let message = format!("Unexpected response when trying to obtain Azure token, {}", &err);
+                    error!("{}", &message);
                     error = true;
+                    for databricks in &databricks_list {
+                        outcomes.push(Outcome::failed("azure", "databricks", &databricks.url, "jobs", Instant::now(), &message).of(&databricks.id()));
+                    }
                 }
             }
             if error {
@@ -362,10 +516,106 @@ impl Azure {
         }
     }
 
-    pub async fn pause(self, order: bool) {
+    async fn status_aks(&self, selection: &HashSet<String>, statuses: &mut Vec<Status>) {
+        let aks_list: Vec<&AKS> = self.aks.iter().flatten().filter(|a| selection.contains(&a.id())).collect();
+        if !aks_list.is_empty() {
+            // Create a http client
+            let client = reqwest::Client::builder().connection_verbose(true).user_agent(USER_AGENT).build().expect("Client::new()");
+            let token = self.get_azure_token(&client, format!("{AZURE_URL}/.default")).await;
+            match token {
+                Ok(token) => {
+                    // Get the managed clusters concurrently and process them in order
+                    let (client, token) = (&client, &token);
+                    let requests: Vec<_> = aks_list.iter().map(|aks| async move { (aks, self.get_aks(client, token, aks).await) }).collect();
+                    let mut clusters = stream::iter(requests).buffered(provider::concurrency(self.concurrency));
+                    while let Some((aks, cluster)) = clusters.next().await {
+                        let state = match cluster {
+                            Ok(cluster) => cluster.properties.power_state.code.to_lowercase(),
+                            Err(err) => {
+                                error!("Unexpected response when trying to get status of AKS {}, {}", &aks.resource_name, &err);
+                                String::from("unknown")
+                            }
+                        };
+                        statuses.push(Status::new("azure", "aks", &aks.resource_group_name, &aks.resource_name, &state));
+                    }
+                },
+                Err(err) => {
+                    error!("Unexpected response when trying to obtain Azure token, {}", &err);
+                    for aks in &aks_list {
+                        statuses.push(Status::new("azure", "aks", &aks.resource_group_name, &aks.resource_name, "unknown"));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn status_databricks(&self, selection: &HashSet<String>, statuses: &mut Vec<Status>) {
+        let databricks_list: Vec<&Databricks> = self.databricks.iter().flatten().filter(|d| selection.contains(&d.id())).collect();
+        if !databricks_list.is_empty() {
+            // Create a http client
+            let client = reqwest::Client::builder().connection_verbose(true).user_agent(USER_AGENT).build().expect("Client::new()");
+            let token = self.get_azure_token(&client, format!("{AZURE_DATABRICKS_SCOPE}/.default")).await;
+            match token {
+                Ok(token) => {
+                    for databricks in &databricks_list {
+                        // Build URL (Calling unwrap is safe in join because path is valid)
+                        let url = reqwest::Url::parse(&databricks.url).map(|u| u.join("/api/2.1/jobs/list").unwrap());
+                        match url {
+                            Ok(url) => match self.get_databricks_jobs(&client, &token, url).await {
+                                Ok(jobs) => for job in plan_databricks_jobs(databricks, jobs, "check") {
+                                    // Safe unwrap since schedule is checked in plan
+                                    let state = job.settings.schedule.unwrap().pause_status.to_lowercase();
+                                    statuses.push(Status::new("azure", "databricks job", &databricks.url, &job.settings.name, &state));
+                                },
+                                Err(err) => error!("Error when trying to get Databricks jobs list, {}", &err)
+                            },
+                            Err(err) => error!("Unexpected error when trying to parse Databricks URL, {}", &err)
+                        }
+                    }
+                },
+                Err(err) => error!("Unexpected response when trying to obtain Azure token, {}", &err)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for Azure {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        let aks = self.aks.iter().flatten().map(|a| Resource::new(a.id(), "aks", &a.resource_group_name, &a.resource_name, &a.depends_on, &a.groups));
+        let databricks = self.databricks.iter().flatten().map(|d| Resource::new(d.id(), "databricks", &d.url, "jobs", &d.depends_on, &d.groups));
+        aks.chain(databricks).collect()
+    }
+
+    async fn execute(&self, action: Action, dry_run: bool, selection: &HashSet<String>) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        if self.has_basic_configuration() {
+            self.pause_aks(action, dry_run, selection, &mut outcomes).await;
+            self.pause_databricks(action, dry_run, selection, None, &mut outcomes).await;
+        }
+        outcomes
+    }
+
+    async fn restore(&self, dry_run: bool, selection: &HashSet<String>, stopped: &HashMap<String, Vec<String>>) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        if self.has_basic_configuration() {
+            // AKS are started as usual and only the Databricks jobs paused by cerdito are unpaused
+            self.pause_aks(Action::Start, dry_run, selection, &mut outcomes).await;
+            self.pause_databricks(Action::Start, dry_run, selection, Some(stopped), &mut outcomes).await;
+        }
+        outcomes
+    }
+
+    async fn status(&self, selection: &HashSet<String>) -> Vec<Status> {
+        let mut statuses = Vec::new();
         if self.has_basic_configuration() {
-            self.pause_aks(order).await;
-            self.pause_databricks(order).await;
+            self.status_aks(selection, &mut statuses).await;
+            self.status_databricks(selection, &mut statuses).await;
         }
+        statuses
     }
 }
@@ -0,0 +1,185 @@
+//
+// calendar.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+use chrono::{Days, NaiveDate};
+use log::{debug, warn};
+use std::fs;
+
+use crate::models::{Calendar, CalendarMode};
+
+// Calendar with all its dates loaded
+#[derive(Debug)]
+pub struct Dates {
+    pub name: String,
+    pub mode: CalendarMode,
+    // Inclusive date ranges
+    ranges: Vec<(NaiveDate, NaiveDate)>
+}
+
+impl Dates {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.ranges.iter().any(|(from, to)| *from <= date && date <= *to)
+    }
+
+    pub fn days_from(&self, date: NaiveDate) -> Vec<NaiveDate> {
+        // All days of calendar from given date in order
+        let mut days: Vec<NaiveDate> = self.ranges.iter()
+            .flat_map(|(from, to)| from.iter_days().take_while(move |d| d <= to))
+            .filter(|d| *d >= date)
+            .collect();
+        days.sort();
+        days.dedup();
+        days
+    }
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| format!("Invalid date {:?}, {}", date, e))
+}
+
+fn parse_ics_date(value: &str) -> Result<NaiveDate, String> {
+    // Both dates (20241225) and date times (20241225T090000Z) are accepted, only the date is used
+    value.get(..8)
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+        .ok_or_else(|| format!("Invalid ICS date {:?}", value))
+}
+
+fn parse_ics(content: &str) -> Result<Vec<(NaiveDate, NaiveDate)>, String> {
+    let mut ranges = Vec::new();
+    // Unfold lines, a line that starts with a space or a tab continues the previous one
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match (line.strip_prefix(' ').or(line.strip_prefix('\t')), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string())
+        }
+    }
+    let (mut start, mut end, mut ignored) = (None, None, Vec::new());
+    for line in lines {
+        // Property name (without parameters) and value
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let name = name.split(';').next().unwrap_or_default().to_uppercase();
+        match name.as_str() {
+            "BEGIN" if value == "VEVENT" => (start, end, ignored) = (None, None, Vec::new()),
+            "DTSTART" => start = Some(parse_ics_date(value)?),
+            // Ends of all-day events and at midnight do not include their day
+            "DTEND" => end = Some((parse_ics_date(value)?, matches!(value.get(8..), None | Some("" | "T000000" | "T000000Z")))),
+            "END" if value == "VEVENT" => if let Some(start) = start {
+                // The end of all-day events (and of those ending at midnight) is exclusive
+                let end = match end {
+                    Some((end, true)) if end > start => end - Days::new(1),
+                    Some((end, _)) => end.max(start),
+                    None => start
+                };
+                // Recurrences and durations are not supported, only the first occurrence is used
+                if !ignored.is_empty() {
+                    warn!("Ignoring {} of ICS event starting {}, only its dates from DTSTART to DTEND are used", ignored.join(", "), start);
+                }
+                ranges.push((start, end));
+            },
+            "RRULE" | "RDATE" | "EXDATE" | "DURATION" if !ignored.contains(&name) => ignored.push(name),
+            _ => ()
+        }
+    }
+    Ok(ranges)
+}
+
+pub fn load(calendar: &Calendar) -> Result<Dates, String> {
+    let mut ranges = Vec::new();
+    // Import dates from ICS file (if any)
+    if let Some(ics) = &calendar.ics {
+        let content = fs::read_to_string(ics).map_err(|e| format!("Cannot read ICS file {} of calendar {}, {}", ics, &calendar.name, e))?;
+        ranges.extend(parse_ics(&content).map_err(|e| format!("{} in ICS file {} of calendar {}", e, ics, &calendar.name))?);
+    }
+    // Add inline date ranges
+    for range in &calendar.dates {
+        let from = parse_date(&range.from)?;
+        let to = match &range.to {
+            Some(to) => parse_date(to)?,
+            None => from
+        };
+        ranges.push((from, to));
+    }
+    debug!("Calendar {} has {} date ranges", &calendar.name, ranges.len());
+    Ok(Dates {
+        name: calendar.name.clone(),
+        mode: calendar.mode,
+        ranges
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        parse_date(value).unwrap()
+    }
+
+    fn dates(ranges: &[(&str, &str)]) -> Dates {
+        Dates {
+            name: String::from("test"),
+            mode: CalendarMode::Skip,
+            ranges: ranges.iter().map(|(from, to)| (date(from), date(to))).collect()
+        }
+    }
+
+    #[test]
+    fn all_day_events_end_is_exclusive() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20241225\r\nDTEND;VALUE=DATE:20241226\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20241230\r\nDTEND;VALUE=DATE:20250102\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20250106\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        assert_eq!(parse_ics(ics).unwrap(), vec![
+            (date("2024-12-25"), date("2024-12-25")),
+            (date("2024-12-30"), date("2025-01-01")),
+            (date("2025-01-06"), date("2025-01-06"))
+        ]);
+    }
+
+    #[test]
+    fn timed_events_end_is_inclusive_unless_at_midnight() {
+        let ics = "BEGIN:VEVENT\nDTSTART:20241224T090000Z\nDTEND:20241226T000000Z\nEND:VEVENT\n\
+            BEGIN:VEVENT\nDTSTART;TZID=Europe/Madrid:20241231T180000\nDTEND;TZID=Europe/Madrid:20241231T200000\nEND:VEVENT\n\
+            BEGIN:VEVENT\nDTSTART:20250102T220000\nDTEND:20250103T000000\nEND:VEVENT\n\
+            BEGIN:VEVENT\nDTSTART:20250106T000000Z\nDTEND:20250106T000000Z\nEND:VEVENT\n\
+            BEGIN:VEVENT\nDTSTART:20250107T090000Z\nDTEND:20250108T000100Z\nEND:VEVENT\n";
+        assert_eq!(parse_ics(ics).unwrap(), vec![
+            (date("2024-12-24"), date("2024-12-25")),
+            (date("2024-12-31"), date("2024-12-31")),
+            (date("2025-01-02"), date("2025-01-02")),
+            (date("2025-01-06"), date("2025-01-06")),
+            (date("2025-01-07"), date("2025-01-08"))
+        ]);
+    }
+
+    #[test]
+    fn folded_lines_are_unfolded() {
+        let ics = "BEGIN:VEVENT\nDTSTART;VALUE=\n DATE:20241225\nDTEND;VALUE=DATE:2024\n\t1227\nEND:VEVENT\n";
+        assert_eq!(parse_ics(ics).unwrap(), vec![(date("2024-12-25"), date("2024-12-26"))]);
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        assert!(parse_ics("BEGIN:VEVENT\nDTSTART:2024-12-25\nEND:VEVENT\n").is_err());
+    }
+
+    #[test]
+    fn days_from_are_sorted_and_unique() {
+        let dates = dates(&[("2024-12-30", "2025-01-02"), ("2024-12-24", "2024-12-26"), ("2025-01-01", "2025-01-01")]);
+        assert_eq!(dates.days_from(date("2024-12-26")), vec![
+            date("2024-12-26"),
+            date("2024-12-30"),
+            date("2024-12-31"),
+            date("2025-01-01"),
+            date("2025-01-02")
+        ]);
+        assert!(dates.days_from(date("2025-01-03")).is_empty());
+        assert!(dates.contains(date("2024-12-25")));
+        assert!(!dates.contains(date("2024-12-27")));
+    }
+}
@@ -7,7 +7,7 @@
 use figment::{Figment, providers::{Env, Format, Serialized, Toml}};
 use log::debug;
 
-use crate::{models::{Atlas, Azure, Config, Kubernetes}, APP_NAME};
+use crate::{models::{Atlas, Azure, Config, Kubernetes, Lock, Storage}, APP_NAME};
 
 impl Default for Config {
     fn default() -> Self {
@@ -15,23 +15,44 @@ impl Default for Config {
         let atlas = Atlas {
             public_key: None,
             private_key: None,
+            concurrency: None,
             clusters: None
         };
         let azure = Azure {
             tenant_id: None,
             client_id: None,
             client_secret: None,
+            concurrency: None,
             aks: None,
             databricks: None
         };
         let kubernetes = Kubernetes {
             kubeconfig: None,
+            in_cluster: None,
+            wait: None,
+            concurrency: None,
+            clusters: None,
             projects: None
         };
         Config {
             atlas,
             azure,
-            kubernetes
+            kubernetes,
+            schedules: None,
+            calendars: None,
+            storage: Storage {
+                file: None,
+                namespace: None,
+                configmap: None,
+                context: None
+            },
+            lock: Lock {
+                file: None,
+                namespace: None,
+                lease: None,
+                context: None,
+                timeout: None
+            }
         }
     }
 }
@@ -0,0 +1,281 @@
+//
+// daemon.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use chrono_tz::Tz;
+use log::{error, info};
+use std::str::FromStr;
+
+use crate::{calendar::{self, Dates}, models::{Calendar, CalendarMode, Schedule}, provider::Filter, Cerdito};
+
+fn crontab_weekdays(field: &str) -> Result<String, String> {
+    // Crontab numbers weekdays from 0 (or 7) Sunday, cron crate from 1 Sunday to 7 Saturday,
+    // numeric weekdays are expanded and translated, names are left as they are
+    let is_name = |token: &str| token.chars().any(|c| c.is_ascii_alphabetic());
+    if field.split(',').any(is_name) {
+        return match field.split(',').all(is_name) {
+            true => Ok(field.to_string()),
+            false => Err(format!("Cannot mix weekday names and numbers in {:?}", field))
+        };
+    }
+    let mut days = Vec::new();
+    for token in field.split(',') {
+        let (range, step) = match token.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(|| format!("Invalid weekday step in {:?}", field))?),
+            None => (token, 1)
+        };
+        let number = |n: &str| n.parse::<u32>().ok().filter(|n| *n <= 7).ok_or_else(|| format!("Invalid weekday {:?} in {:?}", n, field));
+        let (from, to) = match range.split_once('-') {
+            Some((from, to)) => (number(from)?, number(to)?),
+            None if range == "*" => (0, 6),
+            None if token.contains('/') => (number(range)?, 6),
+            None => (number(range)?, number(range)?)
+        };
+        if from > to {
+            return Err(format!("Invalid weekday range {:?} in {:?}", range, field));
+        }
+        days.extend((from..=to).step_by(step as usize).map(|d| d % 7 + 1));
+    }
+    days.sort();
+    days.dedup();
+    Ok(days.iter().map(|d| d.to_string()).collect::<Vec<String>>().join(","))
+}
+
+fn expression(cron: &str) -> Result<String, String> {
+    // Crontab expressions (without seconds) run at second zero and use crontab weekdays
+    let fields: Vec<&str> = cron.split_whitespace().collect();
+    let any = |field: &str| field == "*" || field == "?";
+    match fields.as_slice() {
+        // Crontab runs when either the day or the weekday matches, but the cron crate needs both
+        [_, _, day, _, weekday] if !any(day) && !any(weekday) => Err(String::from("day of month and weekday cannot be both restricted")),
+        [minute, hour, day, month, weekday] if any(weekday) => Ok(format!("0 {} {} {} {} {}", minute, hour, day, month, weekday)),
+        [minute, hour, day, month, weekday] => Ok(format!("0 {} {} {} {} {}", minute, hour, day, month, crontab_weekdays(weekday)?)),
+        _ => Ok(cron.to_string())
+    }
+}
+
+// Configured schedule ready to compute its runs
+struct Job<'a> {
+    schedule: &'a Schedule,
+    cron: cron::Schedule,
+    // Same cron expression but every day, used to force runs on calendar dates
+    daily: cron::Schedule,
+    timezone: Tz,
+    calendars: Vec<&'a Dates>,
+    next: Option<DateTime<Utc>>
+}
+
+impl<'a> Job<'a> {
+    fn new(schedule: &'a Schedule, calendars: &'a [Dates]) -> Result<Job<'a>, String> {
+        let expression = expression(&schedule.cron).map_err(|e| format!("Invalid cron expression {:?}, {}", &schedule.cron, e))?;
+        let cron = cron::Schedule::from_str(&expression).map_err(|e| format!("Invalid cron expression {:?}, {}", &schedule.cron, e))?;
+        // Keep seconds, minutes and hours of expression
+        let time: Vec<&str> = expression.split_whitespace().take(3).collect();
+        let daily = cron::Schedule::from_str(&format!("{} * * *", time.join(" "))).map_err(|e| format!("Invalid cron expression {:?}, {}", &schedule.cron, e))?;
+        let timezone = Tz::from_str(&schedule.timezone).map_err(|e| format!("Invalid time zone {:?}, {}", &schedule.timezone, e))?;
+        let mut job_calendars = Vec::new();
+        for name in &schedule.calendars {
+            match calendars.iter().find(|c| &c.name == name) {
+                Some(calendar) => job_calendars.push(calendar),
+                None => return Err(format!("Calendar {} is not defined", name))
+            }
+        }
+        Ok(Job {
+            schedule,
+            cron,
+            daily,
+            timezone,
+            calendars: job_calendars,
+            next: None
+        })
+    }
+
+    fn calendar(&self, date: NaiveDate, mode: CalendarMode) -> Option<&str> {
+        // Name of the first calendar of given mode that contains date
+        self.calendars.iter().find(|c| c.mode == mode && c.contains(date)).map(|c| c.name.as_str())
+    }
+
+    fn forced_after(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        // First run at the time of the cron expression on a forced date
+        let mut days: Vec<NaiveDate> = self.calendars.iter()
+            .filter(|c| c.mode == CalendarMode::Force)
+            .flat_map(|c| c.days_from(after.date_naive()))
+            .collect();
+        days.sort();
+        days.dedup();
+        for day in days {
+            // Midnight may not exist with daylight saving time, so start from the day before in UTC
+            let start = (day - Days::new(1)).and_time(Default::default()).and_utc().with_timezone(&self.timezone);
+            if let Some(next) = self.daily.after(&start.max(after)).find(|n| n.date_naive() >= day).filter(|n| n.date_naive() == day) {
+                return Some(next);
+            }
+        }
+        None
+    }
+
+    fn description(&self) -> String {
+        match self.schedule.groups.is_empty() {
+            true => format!("{} of all resources", self.schedule.action),
+            false => format!("{} of groups {}", self.schedule.action, self.schedule.groups.join(", "))
+        }
+    }
+
+    fn schedule_after(&mut self, after: DateTime<Utc>) {
+        // Cron expressions are evaluated in the time zone of the schedule
+        let after = after.with_timezone(&self.timezone);
+        let next = self.cron.after(&after).next();
+        let forced = self.forced_after(after);
+        self.next = next.into_iter().chain(forced).min().map(|n| n.with_timezone(&Utc));
+        match self.next {
+            Some(next) => info!("Next {} at {}", self.description(), next.with_timezone(&self.timezone)),
+            None => info!("There are no more runs of {}", self.description())
+        }
+    }
+}
+
+pub async fn run(cerdito: &Cerdito, schedules: &[Schedule], calendars: &[Calendar]) -> Result<(), String> {
+    if schedules.is_empty() {
+        return Err(String::from("No schedules configured"));
+    }
+    let calendars = calendars.iter().map(calendar::load).collect::<Result<Vec<Dates>, String>>()?;
+    let mut jobs = schedules.iter().map(|s| Job::new(s, &calendars)).collect::<Result<Vec<Job>, String>>()?;
+    let now = Utc::now();
+    for job in jobs.iter_mut() {
+        job.schedule_after(now);
+    }
+    loop {
+        // Wait until the nearest run
+        let next = match jobs.iter().filter_map(|j| j.next).min() {
+            Some(next) => next,
+            None => return Err(String::from("There are no more scheduled runs"))
+        };
+        tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+        // Perform all the runs due in configuration order
+        for job in jobs.iter_mut().filter(|j| j.next.is_some_and(|n| n <= next)) {
+            // Forced dates always run, skipped dates do not
+            let date = next.with_timezone(&job.timezone).date_naive();
+            if let Some(name) = job.calendar(date, CalendarMode::Force) {
+                info!("Running scheduled {} forced by calendar {}", job.description(), name);
+            } else if let Some(name) = job.calendar(date, CalendarMode::Skip) {
+                info!("Skipping scheduled {} on {} because of calendar {}", job.description(), date, name);
+                job.schedule_after(Utc::now().max(next));
+                continue;
+            } else {
+                info!("Running scheduled {}", job.description());
+            }
+            let filter = Filter {
+                groups: job.schedule.groups.clone(),
+                ..Default::default()
+            };
+            let summary = cerdito.run(job.schedule.action, false, &filter).await;
+            summary.log();
+            if !summary.success {
+                error!("Scheduled {} has failed for {} resources", job.description(), summary.failed);
+            }
+            job.schedule_after(Utc::now().max(next));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeDelta, TimeZone, Weekday};
+
+    use super::*;
+    use crate::{models::DateRange, provider::Action};
+
+    fn schedule(cron: &str) -> Schedule {
+        Schedule {
+            action: Action::Stop,
+            cron: cron.to_string(),
+            timezone: String::from("UTC"),
+            groups: Vec::new(),
+            calendars: Vec::new()
+        }
+    }
+
+    fn forced(cron: &str, timezone: &str, dates: &[&str], after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        // First forced run after given time with a calendar that forces given dates
+        let calendar = Calendar {
+            name: String::from("forced"),
+            mode: CalendarMode::Force,
+            ics: None,
+            dates: dates.iter().map(|d| DateRange { from: d.to_string(), to: None }).collect()
+        };
+        let calendars = [calendar::load(&calendar).unwrap()];
+        let schedule = Schedule {
+            timezone: timezone.to_string(),
+            calendars: vec![calendar.name],
+            ..schedule(cron)
+        };
+        let job = Job::new(&schedule, &calendars).unwrap();
+        job.forced_after(after.with_timezone(&job.timezone)).map(|f| f.with_timezone(&Utc))
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn weekdays(cron: &str) -> Vec<Weekday> {
+        // Weekdays of the runs of a week starting on Monday 2024-11-04
+        let schedule = schedule(cron);
+        let job = Job::new(&schedule, &[]).unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 11, 4, 0, 0, 0).unwrap();
+        job.cron.after(&after).take_while(|n| *n < after + TimeDelta::days(7)).map(|n| n.weekday()).collect()
+    }
+
+    #[test]
+    fn crontab_weekdays_are_translated() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 19 * * 1-5"), vec![Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays("0 19 * * 0"), vec![Sun]);
+        assert_eq!(weekdays("0 19 * * 7"), vec![Sun]);
+        assert_eq!(weekdays("0 19 * * 5-7"), vec![Fri, Sat, Sun]);
+        assert_eq!(weekdays("0 19 * * 0,6"), vec![Sat, Sun]);
+        assert_eq!(weekdays("0 19 * * */2"), vec![Tue, Thu, Sat, Sun]);
+        assert_eq!(weekdays("0 19 * * Mon-Fri"), vec![Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays("0 19 * * *").len(), 7);
+    }
+
+    #[test]
+    fn expressions_with_seconds_are_not_translated() {
+        assert_eq!(weekdays("0 0 19 * * 2-6"), weekdays("0 19 * * 1-5"));
+    }
+
+    #[test]
+    fn invalid_weekdays_are_rejected() {
+        for cron in ["0 19 * * 8", "0 19 * * 5-1", "0 19 * * Mon,5", "0 19 * * 1/0", "0 0 1 * 1", "0 0 1-7 * Mon"] {
+            assert!(Job::new(&schedule(cron), &[]).is_err(), "{} must be rejected", cron);
+        }
+    }
+
+    #[test]
+    fn forced_runs_use_the_time_of_the_expression() {
+        // Saturday 2024-11-09 is forced for a schedule of working days
+        let dates = ["2024-11-09", "2024-11-16"];
+        assert_eq!(forced("0 19 * * 1-5", "UTC", &dates, utc(2024, 11, 8, 20, 0)), Some(utc(2024, 11, 9, 19, 0)));
+        assert_eq!(forced("0 19 * * 1-5", "UTC", &dates, utc(2024, 11, 9, 0, 0)), Some(utc(2024, 11, 9, 19, 0)));
+    }
+
+    #[test]
+    fn forced_runs_move_to_the_next_forced_day() {
+        let dates = ["2024-11-09", "2024-11-16"];
+        assert_eq!(forced("0 19 * * 1-5", "UTC", &dates, utc(2024, 11, 9, 19, 0)), Some(utc(2024, 11, 16, 19, 0)));
+        assert_eq!(forced("0 19 * * 1-5", "UTC", &dates, utc(2024, 11, 16, 19, 0)), None);
+    }
+
+    #[test]
+    fn forced_runs_follow_daylight_saving_time() {
+        // Europe/Madrid is UTC+2 in summer and UTC+1 in winter
+        let dates = ["2024-03-31", "2024-10-27"];
+        assert_eq!(forced("0 19 * * 1-5", "Europe/Madrid", &dates, utc(2024, 3, 30, 0, 0)), Some(utc(2024, 3, 31, 17, 0)));
+        assert_eq!(forced("0 19 * * 1-5", "Europe/Madrid", &dates, utc(2024, 10, 26, 0, 0)), Some(utc(2024, 10, 27, 18, 0)));
+        // Forced day in the time zone of the schedule, not in UTC
+        assert_eq!(forced("30 0 * * 1-5", "Europe/Madrid", &["2024-10-27"], utc(2024, 10, 26, 0, 0)), Some(utc(2024, 10, 26, 22, 30)));
+        // America/Santiago moves from midnight to 01:00 on 2024-09-08
+        assert_eq!(forced("0 19 * * 1-5", "America/Santiago", &["2024-09-08"], utc(2024, 9, 7, 0, 0)), Some(utc(2024, 9, 8, 22, 0)));
+    }
+}
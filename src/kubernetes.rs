@@ -4,18 +4,991 @@
 // Distributed under terms of the GNU GPLv3 license.
 //
 
-use k8s_openapi::api::apps::v1::Deployment;
-use kube::{api::{Api, Patch, PatchParams}, config::{Kubeconfig, KubeConfigOptions}, Client, Config};
-use log::{debug, error, info};
+use k8s_openapi::api::{apps::v1::{Deployment, StatefulSet}, autoscaling::v2::HorizontalPodAutoscaler, batch::v1::CronJob, core::v1::Namespace};
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use glob::Pattern;
+use kube::{api::{Api, ApiResource, DynamicObject, GroupVersionKind, ListParams, Patch, PatchParams}, config::{Kubeconfig, KubeConfigOptions}, Client, Config, Resource};
+use log::{debug, error, info, log, warn, Level};
+use serde::de::DeserializeOwned;
+use std::{collections::{HashMap, HashSet}, env, fmt::{self, Debug, Display}, time::{Duration, Instant}};
 
-use crate::{models::{Kubernetes, KubernetesProject}, APP_NAME};
+use crate::{models::{Kubernetes, KubernetesCluster, KubernetesProject}, provider::{self, Action, Provider}, report::{Outcome, State, Status}, APP_NAME};
+
+// Annotation used to record the replicas of a workload before scaling it down
+const REPLICAS_ANNOTATION: &str = concat!(env!("CARGO_PKG_NAME"), "/replicas");
+// Annotation used to record the suspend value of a cronjob before suspending it
+const SUSPEND_ANNOTATION: &str = concat!(env!("CARGO_PKG_NAME"), "/suspend");
+// Annotation used to record the min and max replicas of an HPA before neutralising it
+const HPA_ANNOTATION: &str = concat!(env!("CARGO_PKG_NAME"), "/hpa");
+// Annotation used to mark the KEDA ScaledObjects paused by cerdito
+const KEDA_ANNOTATION: &str = concat!(env!("CARGO_PKG_NAME"), "/keda");
+// KEDA annotation to pause ScaledObjects at a fixed number of replicas
+const KEDA_PAUSED_REPLICAS_ANNOTATION: &str = "autoscaling.keda.sh/paused-replicas";
+// Annotation used to record the Argo CD automated sync policy before disabling it
+const AUTOMATED_ANNOTATION: &str = concat!(env!("CARGO_PKG_NAME"), "/automated");
+// Annotation used by owners to keep their workloads out of cerdito stop
+const SKIP_ANNOTATION: &str = concat!(env!("CARGO_PKG_NAME"), "/skip");
+// Seconds between checks when waiting for rollouts
+const WAIT_INTERVAL: u64 = 5;
+
+// Kinds of objects managed by cerdito
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Deployment,
+    StatefulSet,
+    CronJob,
+    HorizontalPodAutoscaler,
+    ScaledObject,
+    Kustomization,
+    HelmRelease,
+    Application
+}
+
+impl Kind {
+    fn resource(&self) -> ApiResource {
+        match self {
+            Kind::Deployment => ApiResource::erase::<Deployment>(&()),
+            Kind::StatefulSet => ApiResource::erase::<StatefulSet>(&()),
+            Kind::CronJob => ApiResource::erase::<CronJob>(&()),
+            Kind::HorizontalPodAutoscaler => ApiResource::erase::<HorizontalPodAutoscaler>(&()),
+            // Custom resources
+            Kind::ScaledObject => ApiResource::from_gvk(&GroupVersionKind::gvk("keda.sh", "v1alpha1", "ScaledObject")),
+            Kind::Kustomization => ApiResource::from_gvk(&GroupVersionKind::gvk("kustomize.toolkit.fluxcd.io", "v1", "Kustomization")),
+            Kind::HelmRelease => ApiResource::from_gvk(&GroupVersionKind::gvk("helm.toolkit.fluxcd.io", "v2", "HelmRelease")),
+            Kind::Application => ApiResource::from_gvk(&GroupVersionKind::gvk("argoproj.io", "v1alpha1", "Application"))
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Kind::Deployment => "deployment",
+            Kind::StatefulSet => "statefulset",
+            Kind::CronJob => "cronjob",
+            Kind::HorizontalPodAutoscaler => "HPA",
+            Kind::ScaledObject => "KEDA ScaledObject",
+            Kind::Kustomization => "Flux Kustomization",
+            Kind::HelmRelease => "Flux HelmRelease",
+            Kind::Application => "Argo CD Application"
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+// Operation to perform over a Kubernetes object
+enum Operation {
+    // Merge patch over the object itself
+    Patch(serde_json::Value),
+    // Set the replicas through the scale subresource
+    Scale(i32)
+}
+
+// Planned change over a Kubernetes object
+struct Change {
+    kind: Kind,
+    namespace: String,
+    name: String,
+    action: String,
+    post_action: String,
+    operations: Vec<Operation>,
+    // Replicas to wait for once the change is applied
+    rollout: Option<i32>
+}
+
+// Changes over an object (and the HPAs that target it) applied in order
+type Unit = Vec<Change>;
+
+// Action to perform over the namespaces of a cluster
+#[derive(Clone, Copy)]
+struct Mode {
+    action: Action,
+    // On start scale up only the workloads with recorded replicas
+    recorded_only: bool,
+    dry_run: bool
+}
+
+// Messages of a plan, logged when the plans are processed in order so they do not interleave
+type Messages = Vec<(Level, String)>;
+
+// Changes of a namespace grouped in phases with the outcomes and messages got while planning
+#[derive(Default)]
+struct Plan {
+    phases: Vec<Vec<Unit>>,
+    outcomes: Vec<Outcome>,
+    messages: Messages,
+    error: bool
+}
+
+impl Plan {
+    fn fail(&mut self, outcome: Outcome) {
+        self.messages.push((Level::Error, outcome.error.clone().unwrap_or_default()));
+        self.error = true;
+        self.outcomes.push(outcome);
+    }
+}
+
+// Workload scaled up that can be waited for
+struct Rollout {
+    resource: String,
+    kind: Kind,
+    namespace: String,
+    name: String,
+    replicas: i32
+}
+
+fn annotation<'a>(object: &'a DynamicObject, key: &str) -> Option<&'a String> {
+    object.metadata.annotations.as_ref().and_then(|a| a.get(key))
+}
+
+async fn plan_scale_down(api: &Api<DynamicObject>, kind: Kind, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    // Get current replicas, if already scaled down there is nothing to record
+    let replicas = api.get_scale(name).await?.spec.and_then(|s| s.replicas).unwrap_or(0);
+    if replicas == 0 {
+        messages.push((Level::Info, format!("Kubernetes {} {} is already scaled down", kind, name)));
+        return Ok(None);
+    }
+    // Record current replicas in an annotation and then scale down
+    Ok(Some(Change {
+        kind,
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: format!("scale down from {} replicas", replicas),
+        post_action: format!("scaled down from {} replicas", replicas),
+        operations: vec![
+            Operation::Patch(serde_json::json!({"metadata": {"annotations": {REPLICAS_ANNOTATION: replicas.to_string()}}})),
+            Operation::Scale(0)
+        ],
+        rollout: None
+    }))
+}
+
+async fn plan_scale_up(api: &Api<DynamicObject>, kind: Kind, namespace: &str, name: &str, default_replicas: Option<i32>, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    // Get recorded replicas from annotation (if any)
+    let object = api.get(name).await?;
+    let recorded = annotation(&object, REPLICAS_ANNOTATION).and_then(|r| r.parse::<i32>().ok());
+    let mut operations = Vec::new();
+    let replicas = match recorded {
+        Some(replicas) => replicas,
+        None => {
+            // Without record do not touch workloads that are already running
+            let current = api.get_scale(name).await?.spec.and_then(|s| s.replicas).unwrap_or(0);
+            if current > 0 {
+                messages.push((Level::Info, format!("Kubernetes {} {} is already scaled up", kind, name)));
+                return Ok(None);
+            }
+            // Nor those that were not scaled down by us when only they must be started
+            let Some(default_replicas) = default_replicas else {
+                messages.push((Level::Info, format!("Kubernetes {} {} was not scaled down by cerdito", kind, name)));
+                return Ok(None);
+            };
+            messages.push((Level::Debug, format!("No recorded replicas for {}, using default value {}", name, default_replicas)));
+            default_replicas
+        }
+    };
+    operations.push(Operation::Scale(replicas));
+    // Remove the record once it has been restored
+    if recorded.is_some() {
+        operations.push(Operation::Patch(serde_json::json!({"metadata": {"annotations": {REPLICAS_ANNOTATION: null}}})));
+    }
+    Ok(Some(Change {
+        kind,
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: format!("scale up to {} replicas", replicas),
+        post_action: format!("scaled up to {} replicas", replicas),
+        operations,
+        rollout: Some(replicas)
+    }))
+}
+
+async fn plan_suspend(api: &Api<DynamicObject>, kind: Kind, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    let object = api.get(name).await?;
+    // If the object was already suspended by us there is nothing to do
+    let suspended = object.data["spec"]["suspend"].as_bool().unwrap_or(false);
+    if suspended || annotation(&object, SUSPEND_ANNOTATION).is_some() {
+        messages.push((Level::Info, format!("Kubernetes {} {} does not need to be suspended", kind, name)));
+        return Ok(None);
+    }
+    // Record current suspend value in an annotation and then suspend
+    Ok(Some(Change {
+        kind,
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: String::from("suspend"),
+        post_action: String::from("suspended"),
+        operations: vec![Operation::Patch(serde_json::json!({
+            "metadata": {"annotations": {SUSPEND_ANNOTATION: suspended.to_string()}},
+            "spec": {"suspend": true}
+        }))],
+        rollout: None
+    }))
+}
+
+async fn plan_resume(api: &Api<DynamicObject>, kind: Kind, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    let object = api.get(name).await?;
+    // Only objects suspended by us are restored to their previous value
+    let recorded = annotation(&object, SUSPEND_ANNOTATION).map(|s| s == "true");
+    match recorded {
+        Some(suspended) => Ok(Some(Change {
+            kind,
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            action: String::from("resume"),
+            post_action: String::from("resumed"),
+            operations: vec![Operation::Patch(serde_json::json!({
+                "metadata": {"annotations": {SUSPEND_ANNOTATION: null}},
+                "spec": {"suspend": suspended}
+            }))],
+            rollout: None
+        })),
+        None => {
+            messages.push((Level::Info, format!("Kubernetes {} {} does not need to be resumed", kind, name)));
+            Ok(None)
+        }
+    }
+}
+
+async fn plan_pause_scaledobject(api: &Api<DynamicObject>, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    let object = api.get(name).await?;
+    // If the ScaledObject is already paused (by us or by someone else) there is nothing to do
+    if annotation(&object, KEDA_PAUSED_REPLICAS_ANNOTATION).is_some() {
+        messages.push((Level::Info, format!("Kubernetes {} {} does not need to be paused", Kind::ScaledObject, name)));
+        return Ok(None);
+    }
+    Ok(Some(Change {
+        kind: Kind::ScaledObject,
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: String::from("pause"),
+        post_action: String::from("paused"),
+        operations: vec![Operation::Patch(serde_json::json!({
+            "metadata": {"annotations": {KEDA_ANNOTATION: "true", KEDA_PAUSED_REPLICAS_ANNOTATION: "0"}}
+        }))],
+        rollout: None
+    }))
+}
+
+async fn plan_unpause_scaledobject(api: &Api<DynamicObject>, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    let object = api.get(name).await?;
+    // Only ScaledObjects paused by us are unpaused
+    if annotation(&object, KEDA_ANNOTATION).is_none() {
+        messages.push((Level::Info, format!("Kubernetes {} {} does not need to be unpaused", Kind::ScaledObject, name)));
+        return Ok(None);
+    }
+    Ok(Some(Change {
+        kind: Kind::ScaledObject,
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: String::from("unpause"),
+        post_action: String::from("unpaused"),
+        operations: vec![Operation::Patch(serde_json::json!({
+            "metadata": {"annotations": {KEDA_ANNOTATION: null, KEDA_PAUSED_REPLICAS_ANNOTATION: null}}
+        }))],
+        rollout: None
+    }))
+}
+
+async fn plan_disable_argocd(api: &Api<DynamicObject>, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    let object = api.get(name).await?;
+    let automated = &object.data["spec"]["syncPolicy"]["automated"];
+    // If the application was already disabled by us or has not auto-sync there is nothing to do
+    if automated.is_null() || annotation(&object, AUTOMATED_ANNOTATION).is_some() {
+        messages.push((Level::Info, format!("Kubernetes {} {} does not need auto-sync to be disabled", Kind::Application, name)));
+        return Ok(None);
+    }
+    // Record current automated sync policy in an annotation and then disable it
+    Ok(Some(Change {
+        kind: Kind::Application,
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: String::from("disable auto-sync of"),
+        post_action: String::from("auto-sync disabled"),
+        operations: vec![Operation::Patch(serde_json::json!({
+            "metadata": {"annotations": {AUTOMATED_ANNOTATION: automated.to_string()}},
+            "spec": {"syncPolicy": {"automated": null}}
+        }))],
+        rollout: None
+    }))
+}
+
+async fn plan_enable_argocd(api: &Api<DynamicObject>, namespace: &str, name: &str, messages: &mut Messages) -> Result<Option<Change>, kube::Error> {
+    let object = api.get(name).await?;
+    // Only applications disabled by us are restored to their previous sync policy
+    let recorded = annotation(&object, AUTOMATED_ANNOTATION).and_then(|a| serde_json::from_str::<serde_json::Value>(a).ok());
+    match recorded {
+        Some(automated) => Ok(Some(Change {
+            kind: Kind::Application,
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            action: String::from("enable auto-sync of"),
+            post_action: String::from("auto-sync enabled"),
+            operations: vec![Operation::Patch(serde_json::json!({
+                "metadata": {"annotations": {AUTOMATED_ANNOTATION: null}},
+                "spec": {"syncPolicy": {"automated": automated}}
+            }))],
+            rollout: None
+        })),
+        None => {
+            messages.push((Level::Info, format!("Kubernetes {} {} does not need auto-sync to be enabled", Kind::Application, name)));
+            Ok(None)
+        }
+    }
+}
+
+fn plan_hpas(hpas: &[HorizontalPodAutoscaler], kind: Kind, name: &str, action: Action, messages: &mut Messages) -> Vec<Change> {
+    let target_kind = kind.resource().kind;
+    let mut changes = Vec::new();
+    // Only the HPAs that target the given workload
+    let targets = hpas.iter().filter(|h| h.spec.as_ref().is_some_and(|s| s.scale_target_ref.kind == target_kind && s.scale_target_ref.name == name));
+    for hpa in targets {
+        let hpa_name = hpa.metadata.name.clone().unwrap_or_default();
+        let namespace = hpa.metadata.namespace.clone().unwrap_or_default();
+        let recorded = hpa.metadata.annotations.as_ref().and_then(|a| a.get(HPA_ANNOTATION));
+        if action == Action::Stop {
+            // If the HPA was already neutralised by us there is nothing to do
+            if recorded.is_some() {
+                messages.push((Level::Debug, format!("Kubernetes {} {} does not need to be neutralised", Kind::HorizontalPodAutoscaler, hpa_name)));
+                continue;
+            }
+            // Record current min and max replicas in an annotation and pin the HPA to a single replica
+            let (min_replicas, max_replicas) = hpa.spec.as_ref().map(|s| (s.min_replicas.unwrap_or(1), s.max_replicas)).unwrap_or((1, 1));
+            changes.push(Change {
+                kind: Kind::HorizontalPodAutoscaler,
+                namespace,
+                name: hpa_name,
+                action: String::from("neutralise"),
+                post_action: String::from("neutralised"),
+                operations: vec![Operation::Patch(serde_json::json!({
+                    "metadata": {"annotations": {HPA_ANNOTATION: format!("{},{}", min_replicas, max_replicas)}},
+                    "spec": {"minReplicas": 1, "maxReplicas": 1}
+                }))],
+                rollout: None
+            });
+        } else {
+            // Only HPAs neutralised by us are restored to their previous values
+            let recorded = recorded
+                .and_then(|r| r.split_once(','))
+                .and_then(|(min, max)| Some((min.parse::<i32>().ok()?, max.parse::<i32>().ok()?)));
+            match recorded {
+                Some((min_replicas, max_replicas)) => changes.push(Change {
+                    kind: Kind::HorizontalPodAutoscaler,
+                    namespace,
+                    name: hpa_name,
+                    action: format!("restore to {}-{} replicas", min_replicas, max_replicas),
+                    post_action: format!("restored to {}-{} replicas", min_replicas, max_replicas),
+                    operations: vec![Operation::Patch(serde_json::json!({
+                        "metadata": {"annotations": {HPA_ANNOTATION: null}},
+                        "spec": {"minReplicas": min_replicas, "maxReplicas": max_replicas}
+                    }))],
+                    rollout: None
+                }),
+                None => messages.push((Level::Debug, format!("Kubernetes {} {} does not need to be restored", Kind::HorizontalPodAutoscaler, hpa_name)))
+            }
+        }
+    }
+    changes
+}
+
+async fn list_names<K>(api: &Api<K>, names: &mut Vec<String>, params: &ListParams) -> Result<(), kube::Error>
+where K: Resource + Clone + DeserializeOwned + Debug {
+    // Add the names of listed objects that are not already in the list
+    for object in api.list(params).await? {
+        if let Some(name) = object.meta().name.clone() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn object_namespace<'a>(project: &'a KubernetesProject, kind: Kind, namespace: &'a str) -> &'a str {
+    // Flux objects can live in other namespace and Argo CD applications live in its own namespace
+    match kind {
+        Kind::Kustomization | Kind::HelmRelease => project.flux_namespace.as_deref().unwrap_or(namespace),
+        Kind::Application => project.argocd_namespace.as_str(),
+        _ => namespace
+    }
+}
+
+async fn resolve_names(api: &Api<DynamicObject>, project: &KubernetesProject, kind: Kind) -> Result<Vec<String>, kube::Error> {
+    let (mut names, list_params) = match kind {
+        Kind::Deployment | Kind::StatefulSet => {
+            // Resolve workloads by selector (or all) at run time
+            let list_params = if project.all {
+                Some(ListParams::default())
+            } else {
+                project.selector.as_ref().map(|selector| ListParams::default().labels(selector))
+            };
+            let names = match kind {
+                Kind::StatefulSet => project.statefulsets.clone(),
+                _ => project.deployments.clone()
+            };
+            (names, list_params)
+        },
+        Kind::CronJob => (project.cronjobs.clone(), project.all_cronjobs.then(ListParams::default)),
+        Kind::ScaledObject => (project.scaledobjects.clone(), project.all_scaledobjects.then(ListParams::default)),
+        Kind::Kustomization => (project.flux_kustomizations.clone(), None),
+        Kind::HelmRelease => (project.flux_helmreleases.clone(), None),
+        Kind::Application => (project.argocd_applications.clone(), None),
+        Kind::HorizontalPodAutoscaler => (Vec::new(), None)
+    };
+    if let Some(list_params) = list_params {
+        list_names(api, &mut names, &list_params).await?;
+    }
+    Ok(names)
+}
+
+async fn plan_objects(client: &Client, project: &KubernetesProject, kind: Kind, namespace: &str, mode: Mode, plan: &mut Plan) -> Vec<Unit> {
+    let action = mode.action;
+    let mut units = Vec::new();
+    let start = Instant::now();
+    let namespace = object_namespace(project, kind, namespace);
+    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), namespace, &kind.resource());
+    let names = match resolve_names(&api, project, kind).await {
+        Ok(names) => names,
+        Err(err) => {
+            let message = format!("Error when trying to get {} list in {}, {}", kind, namespace, err);
+            plan.fail(Outcome::failed("kubernetes", &kind.to_string(), namespace, "*", start, &message).of(&project.id()));
+            Vec::new()
+        }
+    };
+    // Get HPAs of namespace to handle those that target the workloads
+    let hpas = match kind {
+        Kind::Deployment | Kind::StatefulSet if !names.is_empty() => {
+            let hpa_api: Api<HorizontalPodAutoscaler> = Api::namespaced(client.clone(), namespace);
+            match hpa_api.list(&ListParams::default()).await {
+                Ok(list) => list.items,
+                Err(err) => {
+                    let message = format!("Error when trying to get HPA list in {}, {}", namespace, err);
+                    plan.fail(Outcome::failed("kubernetes", &Kind::HorizontalPodAutoscaler.to_string(), namespace, "*", start, &message).of(&project.id()));
+                    Vec::new()
+                }
+            }
+        },
+        _ => Vec::new()
+    };
+    for name in &names {
+        let start = Instant::now();
+        // Workloads annotated to opt out are not stopped
+        if action == Action::Stop {
+            match api.get(name).await {
+                Ok(object) => if provider::opted_out(annotation(&object, SKIP_ANNOTATION)) {
+                    let reason = format!("opted out with annotation {}", SKIP_ANNOTATION);
+                    plan.messages.push((Level::Info, format!("Skipping Kubernetes {} {} in {}, {}", kind, name, namespace, &reason)));
+                    plan.outcomes.push(Outcome::skipped("kubernetes", &kind.to_string(), namespace, name, start, &reason).of(&project.id()));
+                    continue;
+                },
+                Err(err) => {
+                    let message = format!("Something has gone wrong getting {} {} in {}, {}", kind, name, namespace, err);
+                    plan.fail(Outcome::failed("kubernetes", &kind.to_string(), namespace, name, start, &message).of(&project.id()));
+                    continue;
+                }
+            }
+        }
+        let messages = &mut plan.messages;
+        let result = match (kind, action) {
+            (Kind::Deployment | Kind::StatefulSet, Action::Stop) => plan_scale_down(&api, kind, namespace, name, messages).await,
+            (Kind::Deployment | Kind::StatefulSet, Action::Start) => plan_scale_up(&api, kind, namespace, name, (!mode.recorded_only).then_some(project.default_replicas), messages).await,
+            (Kind::ScaledObject, Action::Stop) => plan_pause_scaledobject(&api, namespace, name, messages).await,
+            (Kind::ScaledObject, Action::Start) => plan_unpause_scaledobject(&api, namespace, name, messages).await,
+            (Kind::Application, Action::Stop) => plan_disable_argocd(&api, namespace, name, messages).await,
+            (Kind::Application, Action::Start) => plan_enable_argocd(&api, namespace, name, messages).await,
+            (_, Action::Stop) => plan_suspend(&api, kind, namespace, name, messages).await,
+            (_, Action::Start) => plan_resume(&api, kind, namespace, name, messages).await
+        };
+        match result {
+            Ok(change) => {
+                // Objects that do not need any change are skipped
+                if change.is_none() {
+                    plan.outcomes.push(Outcome::new("kubernetes", &kind.to_string(), namespace, name, State::Skipped, start).of(&project.id()));
+                }
+                // On stop neutralise HPAs before scaling down so they do not fight back and on
+                // start restore them once the workload is scaled up
+                let mut unit = Vec::new();
+                if action == Action::Stop {
+                    unit.extend(plan_hpas(&hpas, kind, name, action, &mut plan.messages));
+                    unit.extend(change);
+                } else {
+                    unit.extend(change);
+                    unit.extend(plan_hpas(&hpas, kind, name, action, &mut plan.messages));
+                }
+                if !unit.is_empty() {
+                    units.push(unit);
+                }
+            },
+            Err(err) => {
+                let message = format!("Something has gone wrong getting {} {} in {}, {}", kind, name, namespace, err);
+                plan.fail(Outcome::failed("kubernetes", &kind.to_string(), namespace, name, start, &message).of(&project.id()));
+            }
+        }
+    }
+    units
+}
+
+async fn resolve_namespaces(client: &Client, project: &KubernetesProject, pattern: Option<&Pattern>) -> Result<Vec<String>, kube::Error> {
+    let mut namespaces: Vec<String> = project.namespace.iter().cloned().collect();
+    if project.namespace_selector.is_some() || pattern.is_some() {
+        // Discover namespaces by label selector and / or pattern
+        let api: Api<Namespace> = Api::all(client.clone());
+        let params = match &project.namespace_selector {
+            Some(selector) => ListParams::default().labels(selector),
+            None => ListParams::default()
+        };
+        let mut discovered = Vec::new();
+        list_names(&api, &mut discovered, &params).await?;
+        for namespace in discovered {
+            if pattern.is_none_or(|p| p.matches(&namespace)) && !namespaces.contains(&namespace) {
+                namespaces.push(namespace);
+            }
+        }
+    }
+    Ok(namespaces)
+}
+
+async fn plan_namespace(client: &Client, project: &KubernetesProject, namespace: &str, mode: Mode) -> Plan {
+    // On stop suspend GitOps reconciliation before anything else so it does not revert the changes,
+    // then cronjobs and KEDA so they do not scale up again and finally scale down, on start
+    // everything goes in the reverse order. Objects of the same phase are independent.
+    let kinds = match mode.action {
+        Action::Stop => vec![vec![Kind::Kustomization, Kind::HelmRelease, Kind::Application], vec![Kind::CronJob, Kind::ScaledObject], vec![Kind::Deployment, Kind::StatefulSet]],
+        Action::Start => vec![vec![Kind::ScaledObject], vec![Kind::Deployment, Kind::StatefulSet], vec![Kind::CronJob, Kind::Kustomization, Kind::HelmRelease, Kind::Application]]
+    };
+    let mut plan = Plan::default();
+    for phase in kinds {
+        let mut units = Vec::new();
+        for kind in phase {
+            units.extend(plan_objects(client, project, kind, namespace, mode, &mut plan).await);
+        }
+        plan.phases.push(units);
+    }
+    plan
+}
+
+async fn apply(client: &Client, change: &Change, params: &PatchParams) -> Result<(), kube::Error> {
+    // Apply all operations of change in order
+    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), &change.namespace, &change.kind.resource());
+    for operation in &change.operations {
+        match operation {
+            Operation::Patch(patch) => {
+                api.patch(&change.name, params, &Patch::Merge(patch)).await?;
+            },
+            Operation::Scale(replicas) => {
+                api.patch_scale(&change.name, params, &Patch::Merge(serde_json::json!({"spec": {"replicas": replicas}}))).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn ready_replicas(client: &Client, rollout: &Rollout) -> Result<i32, kube::Error> {
+    // Get the ready replicas from the status of the workload
+    let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), &rollout.namespace, &rollout.kind.resource());
+    let object = api.get_status(&rollout.name).await?;
+    Ok(object.data["status"]["readyReplicas"].as_i64().unwrap_or(0) as i32)
+}
+
+async fn wait_rollouts(client: &Client, mut rollouts: Vec<Rollout>, timeout: u64, outcomes: &mut Vec<Outcome>) -> bool {
+    let mut error = false;
+    debug!("Waiting up to {} seconds for {} workloads to be ready", timeout, rollouts.len());
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(timeout);
+    loop {
+        let mut pending = Vec::new();
+        for rollout in rollouts {
+            let kind = format!("{} rollout", rollout.kind);
+            match ready_replicas(client, &rollout).await {
+                Ok(ready) if ready >= rollout.replicas => {
+                    info!("Kubernetes {} {} in {} is ready ({} replicas)", rollout.kind, &rollout.name, &rollout.namespace, ready);
+                    outcomes.push(Outcome::new("kubernetes", &kind, &rollout.namespace, &rollout.name, State::Done, start).of(&rollout.resource));
+                },
+                Ok(_) => pending.push(rollout),
+                Err(err) => {
+                    let message = format!("Something has gone wrong checking {} {} in {}, {}", rollout.kind, &rollout.name, &rollout.namespace, err);
+                    error!("{}", &message);
+                    error = true;
+                    outcomes.push(Outcome::failed("kubernetes", &kind, &rollout.namespace, &rollout.name, start, &message).of(&rollout.resource));
+                }
+            }
+        }
+        rollouts = pending;
+        if rollouts.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            for rollout in rollouts {
+                let message = format!("Timeout waiting for {} {} in {} to be ready", rollout.kind, &rollout.name, &rollout.namespace);
+                error!("{}", &message);
+                outcomes.push(Outcome::failed("kubernetes", &format!("{} rollout", rollout.kind), &rollout.namespace, &rollout.name, start, &message).of(&rollout.resource));
+            }
+            error = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(WAIT_INTERVAL)).await;
+    }
+    error
+}
+
+async fn kubeconfig_config(kubeconfig: Option<&String>, context: Option<&String>) -> Option<Config> {
+    // Read kubeconfig
+    let kubeconfig = if let Some(kubeconfig) = kubeconfig {
+        // Use user defined kubeconfig location
+        debug!("Using kubeconfig file {}", kubeconfig);
+        Kubeconfig::read_from(std::path::Path::new(kubeconfig))
+    } else {
+        // Use default kubeconfig location
+        debug!("Using default kubeconfig file location");
+        Kubeconfig::read()
+    };
+    // Use given context or the current one
+    let options = KubeConfigOptions {
+        context: context.cloned(),
+        ..Default::default()
+    };
+    match kubeconfig {
+        Ok(kubeconfig) => {
+            // Read config from kubeconfig
+            match Config::from_custom_kubeconfig(kubeconfig, &options).await {
+                Ok(config) => return Some(config),
+                Err(err) => error!("There has been a problem reading config from kubeconfig, {}", err)
+            }
+        },
+        Err(err) => error!("There has been a problem with kubeconfig, {}", err)
+    }
+    None
+}
+
+fn in_cluster_config() -> Option<Config> {
+    // Read config from the service account of the pod
+    debug!("Using in-cluster configuration");
+    match Config::incluster() {
+        Ok(config) => Some(config),
+        Err(err) => {
+            error!("There has been a problem reading in-cluster config, {}", err);
+            None
+        }
+    }
+}
+
+pub async fn client(kubeconfig: Option<&String>, context: Option<&String>, in_cluster: bool) -> Option<Client> {
+    let config = if in_cluster {
+        in_cluster_config()
+    } else if kubeconfig.is_none() && env::var("KUBERNETES_SERVICE_HOST").is_ok() && Kubeconfig::read().is_err() {
+        // Running inside a pod without kubeconfig, fall back to in-cluster configuration
+        info!("No kubeconfig found, falling back to in-cluster configuration");
+        in_cluster_config()
+    } else {
+        kubeconfig_config(kubeconfig, context).await
+    };
+    // Create a k8s client
+    match Client::try_from(config?) {
+        Ok(client) => Some(client),
+        Err(err) => {
+            error!("Kubernetes client cannot be configured, {}", err);
+            None
+        }
+    }
+}
+
+fn rbac_role(name: &str, namespace: &str, role: &str, role_namespace: &str, rules: &str) -> String {
+    // Role and binding to the service account of the objects that cerdito keeps in a namespace
+    format!(r#"---
+apiVersion: rbac.authorization.k8s.io/v1
+kind: Role
+metadata:
+  name: {role}
+  namespace: {role_namespace}
+rules:
+{rules}---
+apiVersion: rbac.authorization.k8s.io/v1
+kind: RoleBinding
+metadata:
+  name: {role}
+  namespace: {role_namespace}
+roleRef:
+  apiGroup: rbac.authorization.k8s.io
+  kind: Role
+  name: {role}
+subjects:
+  - kind: ServiceAccount
+    name: {name}
+    namespace: {namespace}
+"#)
+}
+
+pub fn rbac(name: &str, namespace: &str, storage_namespace: Option<&str>, lock_namespace: Option<&str>) -> String {
+    // The ConfigMap used to save the state (if any)
+    let storage = storage_namespace.map(|n| rbac_role(name, namespace, &format!("{}-state", name), n, r#"  - apiGroups: [""]
+    resources: ["configmaps"]
+    verbs: ["get", "create", "patch"]
+"#)).unwrap_or_default();
+    // The Lease used as lock (if any)
+    let lock = lock_namespace.map(|n| rbac_role(name, namespace, &format!("{}-lock", name), n, r#"  - apiGroups: ["coordination.k8s.io"]
+    resources: ["leases"]
+    verbs: ["get", "create", "update"]
+"#)).unwrap_or_default();
+    // Minimal RBAC to run cerdito inside the cluster it manages
+    format!(r#"apiVersion: v1
+kind: ServiceAccount
+metadata:
+  name: {name}
+  namespace: {namespace}
+---
+apiVersion: rbac.authorization.k8s.io/v1
+kind: ClusterRole
+metadata:
+  name: {name}
+rules:
+  - apiGroups: [""]
+    resources: ["namespaces"]
+    verbs: ["list"]
+  - apiGroups: ["apps"]
+    resources: ["deployments", "statefulsets"]
+    verbs: ["get", "list", "patch"]
+  - apiGroups: ["apps"]
+    resources: ["deployments/scale", "statefulsets/scale"]
+    verbs: ["get", "patch"]
+  - apiGroups: ["apps"]
+    resources: ["deployments/status", "statefulsets/status"]
+    verbs: ["get"]
+  - apiGroups: ["batch"]
+    resources: ["cronjobs"]
+    verbs: ["get", "list", "patch"]
+  - apiGroups: ["autoscaling"]
+    resources: ["horizontalpodautoscalers"]
+    verbs: ["list", "patch"]
+  - apiGroups: ["keda.sh"]
+    resources: ["scaledobjects"]
+    verbs: ["get", "list", "patch"]
+  - apiGroups: ["kustomize.toolkit.fluxcd.io"]
+    resources: ["kustomizations"]
+    verbs: ["get", "patch"]
+  - apiGroups: ["helm.toolkit.fluxcd.io"]
+    resources: ["helmreleases"]
+    verbs: ["get", "patch"]
+  - apiGroups: ["argoproj.io"]
+    resources: ["applications"]
+    verbs: ["get", "patch"]
+---
+apiVersion: rbac.authorization.k8s.io/v1
+kind: ClusterRoleBinding
+metadata:
+  name: {name}
+roleRef:
+  apiGroup: rbac.authorization.k8s.io
+  kind: ClusterRole
+  name: {name}
+subjects:
+  - kind: ServiceAccount
+    name: {name}
+    namespace: {namespace}
+{storage}{lock}"#)
+}
+
+async fn status_cluster(client: &Client, cluster: &str, projects: &[&KubernetesProject], statuses: &mut Vec<Status>) {
+    for project in projects {
+        // Parse namespace pattern (if any)
+        let pattern = match project.namespace_pattern.as_deref().map(Pattern::new).transpose() {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                error!("Invalid namespace pattern {:?}, {}", &project.namespace_pattern, err);
+                continue;
+            }
+        };
+        // Get the namespaces of project
+        let namespaces = match resolve_namespaces(client, project, pattern.as_ref()).await {
+            Ok(namespaces) => namespaces,
+            Err(err) => {
+                error!("Error when trying to get namespace list, {}", err);
+                continue;
+            }
+        };
+        for namespace in namespaces {
+            // Prefix namespace with the cluster name (if any)
+            let location = match cluster.is_empty() {
+                true => namespace.clone(),
+                false => format!("{}/{}", cluster, &namespace)
+            };
+            for kind in [Kind::Deployment, Kind::StatefulSet, Kind::CronJob] {
+                let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), &namespace, &kind.resource());
+                let names = match resolve_names(&api, project, kind).await {
+                    Ok(names) => names,
+                    Err(err) => {
+                        error!("Error when trying to get {} list in {}, {}", kind, &namespace, err);
+                        continue;
+                    }
+                };
+                for name in names {
+                    let state = match api.get(&name).await {
+                        Ok(object) => match kind {
+                            Kind::CronJob => match object.data["spec"]["suspend"].as_bool().unwrap_or(false) {
+                                true => String::from("suspended"),
+                                false => String::from("active")
+                            },
+                            _ => format!("{}/{} replicas", object.data["status"]["readyReplicas"].as_i64().unwrap_or(0), object.data["spec"]["replicas"].as_i64().unwrap_or(0))
+                        },
+                        Err(err) => {
+                            error!("Something has gone wrong getting {} {} in {}, {}", kind, &name, &namespace, err);
+                            String::from("unknown")
+                        }
+                    };
+                    statuses.push(Status::new("kubernetes", &kind.to_string(), &location, &name, &state));
+                }
+            }
+        }
+    }
+}
+
+async fn pause_cluster(client: &Client, projects: &[&KubernetesProject], mode: Mode, wait: Option<u64>, concurrency: usize, outcomes: &mut Vec<Outcome>) -> bool {
+    let action = mode.action;
+    let (pre_action, post_action) = match action {
+        Action::Stop => ("Scaling down", "scaled down"),
+        Action::Start => ("Scaling up", "scaled up")
+    };
+    let mut error = false;
+    // Define the params to patch objects
+    let params = PatchParams::apply(APP_NAME);
+    let mut targets = Vec::new();
+    for project in projects {
+        let start = Instant::now();
+        // Parse namespace pattern (if any)
+        let pattern = match project.namespace_pattern.as_deref().map(Pattern::new).transpose() {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                let message = format!("Invalid namespace pattern {:?}, {}", &project.namespace_pattern, err);
+                error!("{}", &message);
+                error = true;
+                outcomes.push(Outcome::failed("kubernetes", "namespace", "*", project.namespace_pattern.as_deref().unwrap_or_default(), start, &message).of(&project.id()));
+                continue;
+            }
+        };
+        // Get the namespaces of project
+        let namespaces = match resolve_namespaces(client, project, pattern.as_ref()).await {
+            Ok(namespaces) => namespaces,
+            Err(err) => {
+                let message = format!("Error when trying to get namespace list, {}", err);
+                error!("{}", &message);
+                error = true;
+                outcomes.push(Outcome::failed("kubernetes", "namespace", "*", "*", start, &message).of(&project.id()));
+                continue;
+            }
+        };
+        if namespaces.is_empty() {
+            warn!("No namespaces found for Kubernetes project {:?}", project);
+        }
+        targets.extend(namespaces.into_iter().map(|namespace| (*project, namespace)));
+    }
+    // Plan all changes of the namespaces concurrently before applying them and process the
+    // plans in order
+    let requests: Vec<_> = targets.into_iter().map(|(project, namespace)| async move {
+        let plan = plan_namespace(client, project, &namespace, mode).await;
+        (project.id(), namespace, plan)
+    }).collect();
+    let mut plans = Vec::new();
+    let mut planned = stream::iter(requests).buffered(concurrency);
+    while let Some((resource, namespace, plan)) = planned.next().await {
+        info!("{} Kubernetes project {}", &pre_action, &namespace);
+        for (level, message) in plan.messages {
+            log!(level, "{}", message);
+        }
+        outcomes.extend(plan.outcomes);
+        plans.push((resource, namespace, plan.phases, plan.error));
+    }
+    if mode.dry_run {
+        for (resource, _, phases, project_error) in plans {
+            error |= project_error;
+            for change in phases.into_iter().flatten().flatten() {
+                outcomes.push(Outcome::planned("kubernetes", &change.kind.to_string(), &change.namespace, &change.name, &change.action).of(&resource));
+            }
+        }
+        return error;
+    }
+    // Apply the phases one after another, the objects of a phase concurrently (in all the
+    // namespaces) and the changes of each object in order, processing the results in order
+    let params = &params;
+    let mut rollouts = Vec::new();
+    let count = plans.iter().map(|(_, _, phases, _)| phases.len()).max().unwrap_or(0);
+    for phase in 0..count {
+        let requests: Vec<_> = plans.iter_mut().enumerate()
+            .flat_map(|(index, (_, _, phases, _))| phases.get_mut(phase).map(std::mem::take).unwrap_or_default().into_iter().map(move |unit| (index, unit)))
+            .map(|(index, unit)| async move {
+                let mut results = Vec::new();
+                for change in unit {
+                    let start = Instant::now();
+                    let result = apply(client, &change, params).await;
+                    results.push((change, start, result));
+                }
+                (index, results)
+            })
+            .collect();
+        let mut responses = stream::iter(requests).buffered(concurrency);
+        while let Some((index, results)) = responses.next().await {
+            let (resource, _, _, project_error) = &mut plans[index];
+            for (change, start, result) in results {
+                let kind = change.kind.to_string();
+                match result {
+                    Ok(_) => {
+                        info!("Kubernetes {} {} in {} {}", change.kind, &change.name, &change.namespace, &change.post_action);
+                        outcomes.push(Outcome::new("kubernetes", &kind, &change.namespace, &change.name, State::Done, start).of(resource));
+                        if let Some(replicas) = change.rollout {
+                            rollouts.push(Rollout { resource: resource.clone(), kind: change.kind, namespace: change.namespace, name: change.name, replicas });
+                        }
+                    },
+                    Err(err) => {
+                        let message = format!("Something has gone wrong when trying to {} {} {} in {}, {}", &change.action, change.kind, &change.name, &change.namespace, err);
+                        error!("{}", &message);
+                        *project_error = true;
+                        outcomes.push(Outcome::failed("kubernetes", &kind, &change.namespace, &change.name, start, &message).of(resource));
+                    }
+                }
+            }
+        }
+    }
+    for (_, namespace, _, project_error) in plans {
+        if project_error {
+            error = true;
+            debug!("Some (or all) workloads in Kubernetes project {} has failed to {}", &namespace, &action)
+        } else {
+            debug!("Kubernetes project {} have been {}", &namespace, &post_action)
+        }
+    }
+    // On start wait for workloads to be ready (if configured)
+    if let (Action::Start, Some(wait)) = (action, wait) {
+        error |= wait_rollouts(client, rollouts, wait, outcomes).await;
+    }
+    error
+}
+
+impl KubernetesProject {
+    fn label(&self) -> &str {
+        // Projects are identified by namespace, or by pattern or selector when they have not
+        self.namespace.as_ref().or(self.namespace_pattern.as_ref()).or(self.namespace_selector.as_ref()).map_or("", |n| n.as_str())
+    }
+
+    pub fn id(&self) -> String {
+        match &self.cluster {
+            Some(cluster) => format!("kubernetes/{}/{}", cluster, self.label()),
+            None => format!("kubernetes/{}", self.label())
+        }
+    }
+}
 
 impl Kubernetes {
-    pub fn new(kubeconfig: Option<String>, projects: Option<Vec<KubernetesProject>>) -> Kubernetes {
+    pub fn new(kubeconfig: Option<String>, in_cluster: Option<bool>, wait: Option<u64>, concurrency: Option<usize>, clusters: Option<Vec<KubernetesCluster>>, projects: Option<Vec<KubernetesProject>>) -> Kubernetes {
         debug!("Kubernetes kubeconfig file: {:?}", kubeconfig);
+        debug!("Kubernetes in-cluster configuration: {:?}", in_cluster);
+        debug!("Kubernetes wait timeout: {:?}", wait);
+        debug!("Kubernetes concurrency: {:?}", concurrency);
+        debug!("Kubernetes clusters: {:?}", clusters);
         debug!("Kubernetes projects: {:?}", projects);
         Kubernetes {
             kubeconfig,
+            in_cluster,
+            wait,
+            concurrency,
+            clusters,
             projects
         }
     }
@@ -32,70 +1005,127 @@ impl Kubernetes {
         false
     }
 
-    pub async fn pause(self, order: bool) {
+    fn undefined_cluster<'a>(&self, project: &'a KubernetesProject) -> Option<&'a String> {
+        // Cluster of project when it is not defined
+        project.cluster.as_ref().filter(|c| !self.clusters.iter().flatten().any(|k| &k.name == *c))
+    }
+
+    fn cluster_projects(&self) -> Vec<(KubernetesCluster, Vec<&KubernetesProject>)> {
+        // Projects without cluster use the default kubeconfig and context
+        let default = KubernetesCluster {
+            name: String::new(),
+            kubeconfig: self.kubeconfig.clone(),
+            context: None,
+            in_cluster: self.in_cluster.unwrap_or(false)
+        };
+        let mut clusters = Vec::new();
+        for cluster in std::iter::once(default).chain(self.clusters.iter().flatten().cloned()) {
+            let projects: Vec<&KubernetesProject> = self.projects.iter()
+                .flatten()
+                .filter(|p| p.cluster.as_deref().unwrap_or_default() == cluster.name)
+                .collect();
+            if !projects.is_empty() {
+                // Clusters without kubeconfig use the default one
+                let kubeconfig = cluster.kubeconfig.clone().or(self.kubeconfig.clone());
+                clusters.push((KubernetesCluster { kubeconfig, ..cluster }, projects));
+            }
+        }
+        clusters
+    }
+
+    async fn pause(&self, action: Action, dry_run: bool, selection: &HashSet<String>, recorded_only: bool) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
         if self.has_configuration() {
-            let (pre_action, action, post_action, replicas) = match order {
-                true => ("Scaling down", "scale down", "scaled down", 0),
-                false => ("Scaling up", "scale up", "scaled up", 1)
+            let post_action = match action {
+                Action::Stop => "scaled down",
+                Action::Start => "scaled up"
             };
             let mut error = false;
             debug!("Trying to {} all configured projects", action);
-            // Read kubeconfig
-            let kubeconfig = if self.kubeconfig.is_some() {
-                // Use user defined kubeconfig location
-                let kubeconfig = self.kubeconfig.unwrap();
-                debug!("Using kubeconfig file {}", &kubeconfig);
-                Kubeconfig::read_from(std::path::Path::new(&kubeconfig))
-            } else {
-                // Use default kubeconfig location
-                debug!("Using default kubeconfig file location");
-                Kubeconfig::read()
-            };
-            match kubeconfig {
-                Ok(kubeconfig) => {
-                    // Read config from kubeconfig
-                    match Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await {
-                        Ok(config) => {
-                            // Create a k8s client
-                            match Client::try_from(config) {
-                                Ok(client) => {
-                                    // Define the spec and params to scale deployments to desired replicas
-                                    let patch = Patch::Merge(serde_json::json!({"spec": {"replicas": replicas}}));
-                                    let params = PatchParams::apply(APP_NAME);
-                                    for project in self.projects.unwrap() {
-                                        info!("{} Kubernetes project {}", &pre_action, &project.namespace);
-                                        // Manage deployments
-                                        let deployments: Api<Deployment> = Api::namespaced(client.clone(), &project.namespace);
-                                        for deployment in project.deployments {
-                                            // Perform request
-                                            match deployments.patch_scale(&deployment, &params, &patch).await {
-                                                Ok(_) => info!("Kubernetes deployment {} {}", &deployment, &post_action),
-                                                Err(err) => {
-                                                    error!("Something has gone wrong with deployment {} {}, {}", &deployment, &action, err);
-                                                    error = true;
-                                                }
-                                            }
-                                        }
-                                        if error {
-                                            debug!("Some (or all) deploymens in Kubernetes project {} has failed to {}", &project.namespace, &action)
-                                        } else {
-                                            debug!("Kubernetes project {} have been {}", &project.namespace, &post_action)
-                                        }
-                                    }
-                                    if error {
-                                        debug!("Some (or all) projects have failed to {}", &action)
-                                    } else {
-                                        debug!("All projects have been {}", &post_action)
-                                    }
-                                },
-                                Err(err) => error!("Kubernetes client cannot be configured, {}", err)
-                            }
-                        },
-                        Err(err) => error!("There has been a problem reading config from kubeconfig, {}", err)
+            // Projects of undefined clusters fail, the others go on
+            for project in self.projects.iter().flatten().filter(|p| selection.contains(&p.id())) {
+                if let Some(cluster) = self.undefined_cluster(project) {
+                    let message = format!("Kubernetes cluster {} of project {} is not defined", cluster, project.label());
+                    error!("{}", &message);
+                    error = true;
+                    outcomes.push(Outcome::failed("kubernetes", "cluster", "*", cluster, Instant::now(), &message).of(&project.id()));
+                }
+            }
+            for (cluster, mut projects) in self.cluster_projects() {
+                // Only the selected projects of cluster
+                projects.retain(|p| selection.contains(&p.id()));
+                if projects.is_empty() {
+                    continue;
+                }
+                if !cluster.name.is_empty() {
+                    info!("Using Kubernetes cluster {}", &cluster.name);
+                }
+                let start = Instant::now();
+                let mut cluster_outcomes = Vec::new();
+                match client(cluster.kubeconfig.as_ref(), cluster.context.as_ref(), cluster.in_cluster).await {
+                    Some(client) => error |= pause_cluster(&client, &projects, Mode { action, recorded_only, dry_run }, self.wait, provider::concurrency(self.concurrency), &mut cluster_outcomes).await,
+                    None => {
+                        error = true;
+                        cluster_outcomes.push(Outcome::failed("kubernetes", "cluster", "*", &cluster.name, start, "Kubernetes client cannot be configured"));
                     }
-                },
-                Err(err) => error!("There has been a problem with kubeconfig, {}", err)
+                }
+                // Prefix locations with the cluster name (if any)
+                if !cluster.name.is_empty() {
+                    for outcome in cluster_outcomes.iter_mut() {
+                        outcome.location = format!("{}/{}", &cluster.name, &outcome.location);
+                    }
+                }
+                outcomes.extend(cluster_outcomes);
+            }
+            if error {
+                debug!("Some (or all) projects have failed to {}", &action)
+            } else {
+                debug!("All projects have been {}", &post_action)
+            }
+        }
+        outcomes
+    }
+}
+
+#[async_trait]
+impl Provider for Kubernetes {
+    fn name(&self) -> &'static str {
+        "kubernetes"
+    }
+
+    fn resources(&self) -> Vec<provider::Resource> {
+        self.projects.iter().flatten().map(|p| provider::Resource::new(p.id(), "project", p.cluster.as_deref().unwrap_or("*"), p.label(), &p.depends_on, &p.groups)).collect()
+    }
+
+    async fn execute(&self, action: Action, dry_run: bool, selection: &HashSet<String>) -> Vec<Outcome> {
+        self.pause(action, dry_run, selection, false).await
+    }
+
+    async fn restore(&self, dry_run: bool, selection: &HashSet<String>, _stopped: &HashMap<String, Vec<String>>) -> Vec<Outcome> {
+        // Only the workloads with recorded replicas are scaled up
+        self.pause(Action::Start, dry_run, selection, true).await
+    }
+
+    async fn status(&self, selection: &HashSet<String>) -> Vec<Status> {
+        let mut statuses = Vec::new();
+        if self.has_configuration() {
+            for project in self.projects.iter().flatten().filter(|p| selection.contains(&p.id())) {
+                if let Some(cluster) = self.undefined_cluster(project) {
+                    error!("Kubernetes cluster {} of project {} is not defined", cluster, project.label());
+                    statuses.push(Status::new("kubernetes", "cluster", "*", cluster, "unknown"));
+                }
+            }
+            for (cluster, mut projects) in self.cluster_projects() {
+                // Only the selected projects of cluster
+                projects.retain(|p| selection.contains(&p.id()));
+                if projects.is_empty() {
+                    continue;
+                }
+                if let Some(client) = client(cluster.kubeconfig.as_ref(), cluster.context.as_ref(), cluster.in_cluster).await {
+                    status_cluster(&client, &cluster.name, &projects, &mut statuses).await;
+                }
             }
         }
+        statuses
     }
 }
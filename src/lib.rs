@@ -0,0 +1,232 @@
+//
+// lib.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+pub mod atlas;
+pub mod azure;
+pub mod calendar;
+pub mod config;
+pub mod daemon;
+pub mod kubernetes;
+pub mod lock;
+pub mod models;
+pub mod provider;
+pub mod report;
+pub mod state;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use std::{collections::{HashMap, HashSet}, time::Instant};
+
+use crate::{lock::Locker, models::{Lock, Storage}, state::{SavedState, Store}};
+
+pub use crate::{models::Config, provider::{Action, Filter, Provider}, report::{Outcome, State, Status, Summary}};
+
+pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
+pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Entry point to stop, start and get status of the configured providers
+pub struct Cerdito {
+    providers: Vec<Box<dyn Provider>>,
+    store: Store,
+    locker: Locker
+}
+
+impl Cerdito {
+    pub fn new(config: Config) -> Cerdito {
+        // State and lock are local files or Kubernetes objects reached as the Kubernetes provider
+        let in_cluster = config.kubernetes.in_cluster.unwrap_or(false);
+        let store = Store::new(config.storage, config.kubernetes.kubeconfig.clone(), in_cluster);
+        let locker = Locker::new(config.lock, config.kubernetes.kubeconfig.clone(), in_cluster);
+        // Providers in start order, on stop they go in reverse order
+        let atlas = models::Atlas::new(config.atlas.public_key, config.atlas.private_key, config.atlas.concurrency, config.atlas.clusters);
+        let azure = models::Azure::new(config.azure.tenant_id, config.azure.client_id, config.azure.client_secret, config.azure.concurrency, config.azure.aks, config.azure.databricks);
+        let kubernetes = models::Kubernetes::new(config.kubernetes.kubeconfig, config.kubernetes.in_cluster, config.kubernetes.wait, config.kubernetes.concurrency, config.kubernetes.clusters, config.kubernetes.projects);
+        Cerdito {
+            store,
+            locker,
+            ..Cerdito::with_providers(vec![Box::new(atlas), Box::new(azure), Box::new(kubernetes)])
+        }
+    }
+
+    pub fn with_providers(providers: Vec<Box<dyn Provider>>) -> Cerdito {
+        Cerdito {
+            providers,
+            store: Store::new(Storage::default(), None, false),
+            locker: Locker::new(Lock::default(), None, false)
+        }
+    }
+
+    fn ids(&self) -> Vec<String> {
+        // Ids of all the configured resources
+        self.providers.iter().flat_map(|p| p.resources().into_iter().map(|r| r.id)).collect()
+    }
+
+    fn snoozed(saved: &SavedState) -> HashMap<String, String> {
+        // Resources snoozed until a time that has not yet arrived
+        let now = Utc::now();
+        saved.snoozes.iter()
+            .filter_map(|s| saved.snoozed(&s.resource, now).map(|s| (s.resource.clone(), format!("snoozed until {}", s.until))))
+            .collect()
+    }
+
+    async fn record(&self, action: Action, outcomes: &[Outcome]) -> Result<(), String> {
+        // Resources with some change done on stop and those started without failures
+        let resources = |state: Option<State>| -> HashSet<String> {
+            outcomes.iter()
+                .filter_map(|o| o.resource.clone())
+                .filter(|r| match state {
+                    Some(state) => outcomes.iter().any(|o| o.resource.as_ref() == Some(r) && o.state == state),
+                    None => !outcomes.iter().any(|o| o.resource.as_ref() == Some(r) && o.state == State::Failed)
+                })
+                .collect()
+        };
+        let mut saved = self.store.load().await?;
+        match action {
+            Action::Stop => {
+                let stopped = resources(Some(State::Done));
+                if stopped.is_empty() {
+                    return Ok(());
+                }
+                let now = Utc::now();
+                let mut stopped: Vec<String> = stopped.into_iter().collect();
+                stopped.sort();
+                for resource in stopped {
+                    // Items stopped now and those stopped before that have not been started since
+                    let mut items: Vec<String> = saved.stopped.iter().filter(|s| s.resource == resource).flat_map(|s| s.items.clone())
+                        .chain(outcomes.iter().filter(|o| o.resource.as_ref() == Some(&resource) && o.state == State::Done).filter_map(|o| o.item.clone()))
+                        .collect();
+                    items.sort();
+                    items.dedup();
+                    info!("Recording {} as stopped", &resource);
+                    saved.stopped.retain(|s| s.resource != resource);
+                    saved.stopped.push(state::Stopped { resource, at: now, items });
+                }
+            },
+            Action::Start => {
+                let started = resources(None);
+                if !saved.stopped.iter().any(|s| started.contains(&s.resource)) {
+                    return Ok(());
+                }
+                saved.stopped.retain(|s| !started.contains(&s.resource));
+            }
+        }
+        self.store.save(&saved).await
+    }
+
+    async fn execute(&self, action: Action, dry_run: bool, filter: &Filter, only_previously_stopped: bool) -> Summary {
+        let start = Instant::now();
+        // Only one run can change things at the same time
+        let guard = if dry_run {
+            None
+        } else {
+            match self.locker.acquire().await {
+                Ok(guard) => guard,
+                Err(message) => {
+                    error!("{}", &message);
+                    return Summary::new(&action.to_string(), vec![Outcome::failed(APP_NAME, "lock", "*", "*", start, &message)]);
+                }
+            }
+        };
+        let summary = self.locked_execute(action, dry_run, filter, only_previously_stopped, start).await;
+        if let Some(guard) = guard {
+            guard.release().await;
+        }
+        summary
+    }
+
+    async fn locked_execute(&self, action: Action, dry_run: bool, filter: &Filter, only_previously_stopped: bool, start: Instant) -> Summary {
+        let saved = match self.store.load().await {
+            Ok(saved) => saved,
+            Err(message) if action == Action::Stop || only_previously_stopped => {
+                // Without state it is not known what is snoozed or what has to be started
+                error!("{}", &message);
+                return Summary::new(&action.to_string(), vec![Outcome::failed(APP_NAME, "state", "*", "*", start, &message)]);
+            },
+            Err(message) => {
+                // A plain start does not use the state
+                warn!("{}", message);
+                SavedState::default()
+            }
+        };
+        // Snoozed resources are not stopped and, if asked, only previously stopped are started
+        let skipped = match action {
+            Action::Stop => Cerdito::snoozed(&saved),
+            Action::Start if only_previously_stopped => self.ids().into_iter()
+                .filter(|id| !saved.stopped.iter().any(|s| &s.resource == id))
+                .map(|id| (id, String::from("not previously stopped by cerdito")))
+                .collect(),
+            Action::Start => HashMap::new()
+        };
+        // Inside the resources only what cerdito stopped is started
+        let stopped: Option<HashMap<String, Vec<String>>> = only_previously_stopped.then(|| saved.stopped.iter().map(|s| (s.resource.clone(), s.items.clone())).collect());
+        let outcomes = provider::run(&self.providers, action, dry_run, filter, &skipped, stopped.as_ref()).await;
+        // Keep track of what has been stopped, the run has already been done so it does not fail if it cannot
+        if !dry_run {
+            if let Err(message) = self.record(action, &outcomes).await {
+                warn!("{}, the resources {} in this run are not recorded", message, action.participle());
+            }
+        }
+        Summary::new(&action.to_string(), outcomes)
+    }
+
+    pub async fn run(&self, action: Action, dry_run: bool, filter: &Filter) -> Summary {
+        self.execute(action, dry_run, filter, false).await
+    }
+
+    pub async fn snooze(&self, resource: &str, until: DateTime<Utc>) -> Result<Vec<String>, String> {
+        // Resolve resource name to the ids of the configured resources
+        let filter = Filter {
+            resources: vec![resource.to_string()],
+            ..Default::default()
+        };
+        let mut ids: Vec<String> = self.providers.iter()
+            .flat_map(|p| p.resources().into_iter().filter(|r| filter.matches(p.name(), r)).map(|r| r.id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        if ids.is_empty() {
+            return Err(format!("Resource {} is not configured", resource));
+        }
+        let now = Utc::now();
+        if until <= now {
+            return Err(format!("Snooze time {} is already past", until));
+        }
+        // The state is also saved by start and stop runs, so hold the lock while changing it
+        let guard = self.locker.acquire().await?;
+        let result = self.record_snoozes(&ids, until, now).await;
+        if let Some(guard) = guard {
+            guard.release().await;
+        }
+        result.map(|_| ids)
+    }
+
+    async fn record_snoozes(&self, ids: &[String], until: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), String> {
+        // Record snoozes dropping the expired ones
+        let mut saved = self.store.load().await?;
+        saved.snoozes.retain(|s| s.until > now && !ids.contains(&s.resource));
+        for id in ids {
+            info!("Snoozing {} until {}", id, until);
+            saved.snoozes.push(state::Snooze { resource: id.clone(), until });
+        }
+        self.store.save(&saved).await
+    }
+
+    pub async fn start(&self, dry_run: bool, filter: &Filter) -> Summary {
+        self.run(Action::Start, dry_run, filter).await
+    }
+
+    pub async fn start_previously_stopped(&self, dry_run: bool, filter: &Filter) -> Summary {
+        self.execute(Action::Start, dry_run, filter, true).await
+    }
+
+    pub async fn stop(&self, dry_run: bool, filter: &Filter) -> Summary {
+        self.run(Action::Stop, dry_run, filter).await
+    }
+
+    pub async fn status(&self, filter: &Filter) -> Vec<Status> {
+        provider::status(&self.providers, filter).await
+    }
+}
@@ -0,0 +1,232 @@
+//
+// lock.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+use chrono::{TimeDelta, Utc};
+use fs4::fs_std::FileExt;
+use k8s_openapi::{api::coordination::v1::{Lease, LeaseSpec}, apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta}};
+use kube::api::{Api, PostParams};
+use log::{debug, error, info, warn};
+use std::{env, fs::{self, File, OpenOptions}, io::{Read, Write}, process, time::{Duration, Instant}};
+use tokio::task::JoinHandle;
+
+use crate::{kubernetes, models::Lock, APP_NAME};
+
+// Seconds between attempts while waiting for the lock
+const LOCK_INTERVAL: u64 = 2;
+// Seconds a Lease is valid if it is not renewed
+const LEASE_DURATION: i32 = 30;
+// Seconds between renewals of a held Lease
+const LEASE_RENEW: u64 = 10;
+
+// Result of an attempt to take the lock
+enum Attempt {
+    Acquired(Guard),
+    // Description of who holds the lock
+    Held(String)
+}
+
+// Lock taken, it is released when the guard is released (or dropped for files)
+pub enum Guard {
+    File(File),
+    Lease {
+        api: Api<Lease>,
+        name: String,
+        holder: String,
+        renewal: JoinHandle<()>
+    }
+}
+
+impl Guard {
+    pub async fn release(self) {
+        match self {
+            // Closing the file releases the lock
+            Guard::File(file) => if let Err(err) = file.set_len(0) {
+                warn!("Cannot clear lock file, {}", err);
+            },
+            Guard::Lease { api, name, holder, renewal } => {
+                renewal.abort();
+                // Free the Lease only if it is still ours, otherwise it will expire
+                match api.get(&name).await {
+                    Ok(mut lease) => {
+                        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+                        if spec.holder_identity.as_ref() == Some(&holder) {
+                            spec.holder_identity = None;
+                            spec.renew_time = None;
+                            if let Err(err) = api.replace(&name, &PostParams::default(), &lease).await {
+                                warn!("Cannot release Lease {}, it will expire in {} seconds, {}", &name, LEASE_DURATION, err);
+                            }
+                        }
+                    },
+                    Err(err) => warn!("Cannot release Lease {}, it will expire in {} seconds, {}", &name, LEASE_DURATION, err)
+                }
+            }
+        }
+    }
+}
+
+fn holder() -> String {
+    // Host and process that hold the lock
+    let host = env::var("HOSTNAME").ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok().map(|h| h.trim().to_string()))
+        .filter(|h| !h.is_empty())
+        .unwrap_or(String::from("unknown"));
+    format!("{}/{}", host, process::id())
+}
+
+fn lease(name: &str, holder: &str) -> Lease {
+    let now = MicroTime(Utc::now());
+    Lease {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            ..Default::default()
+        },
+        spec: Some(LeaseSpec {
+            holder_identity: Some(holder.to_string()),
+            lease_duration_seconds: Some(LEASE_DURATION),
+            acquire_time: Some(now.clone()),
+            renew_time: Some(now),
+            ..Default::default()
+        })
+    }
+}
+
+fn renew(api: Api<Lease>, name: String, holder: String) -> JoinHandle<()> {
+    // Keep the Lease renewed while the lock is held
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(LEASE_RENEW)).await;
+            match api.get(&name).await {
+                Ok(mut lease) => {
+                    let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+                    if spec.holder_identity.as_ref() != Some(&holder) {
+                        error!("Lease {} has been taken by {}", &name, spec.holder_identity.as_deref().unwrap_or("nobody"));
+                        break;
+                    }
+                    spec.renew_time = Some(MicroTime(Utc::now()));
+                    match api.replace(&name, &PostParams::default(), &lease).await {
+                        Ok(_) => debug!("Lease {} renewed", &name),
+                        Err(err) => warn!("Cannot renew Lease {}, {}", &name, err)
+                    }
+                },
+                Err(err) => warn!("Cannot renew Lease {}, {}", &name, err)
+            }
+        }
+    })
+}
+
+async fn try_lease(api: &Api<Lease>, namespace: &str, name: &str, holder: &str) -> Result<Attempt, String> {
+    let now = Utc::now();
+    let result = match api.get_opt(name).await {
+        Ok(None) => api.create(&PostParams::default(), &lease(name, holder)).await,
+        Ok(Some(mut current)) => {
+            let spec = current.spec.get_or_insert_with(LeaseSpec::default);
+            // The Lease is held by somebody else until it expires
+            let duration = TimeDelta::seconds(spec.lease_duration_seconds.unwrap_or(LEASE_DURATION).into());
+            if let (Some(by), Some(renewed)) = (&spec.holder_identity, &spec.renew_time) {
+                if by != holder && renewed.0 + duration > now {
+                    return Ok(Attempt::Held(format!("Lease {} in {} is held by {} since {}", name, namespace, by, spec.acquire_time.as_ref().map_or(renewed.0, |t| t.0))));
+                }
+            }
+            // Take it keeping the resource version, so only one run can do it
+            let transitions = spec.lease_transitions.unwrap_or(0) + 1;
+            current.spec = lease(name, holder).spec.map(|s| LeaseSpec { lease_transitions: Some(transitions), ..s });
+            api.replace(name, &PostParams::default(), &current).await
+        },
+        Err(err) => return Err(format!("Cannot get Lease {} in {}, {}", name, namespace, err))
+    };
+    match result {
+        Ok(_) => Ok(Attempt::Acquired(Guard::Lease {
+            api: api.clone(),
+            name: name.to_string(),
+            holder: holder.to_string(),
+            renewal: renew(api.clone(), name.to_string(), holder.to_string())
+        })),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(Attempt::Held(format!("Lease {} in {} has just been taken by another run", name, namespace))),
+        Err(err) => Err(format!("Cannot take Lease {} in {}, {}", name, namespace, err))
+    }
+}
+
+fn try_file(file: &str, holder: &str) -> Result<Attempt, String> {
+    let mut lock = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(file)
+        .map_err(|e| format!("Cannot open lock file {}, {}", file, e))?;
+    match FileExt::try_lock_exclusive(&lock) {
+        Ok(true) => {
+            // Write who holds the lock to tell it to the others
+            lock.set_len(0).and_then(|_| lock.write_all(holder.as_bytes()))
+                .map_err(|e| format!("Cannot write lock file {}, {}", file, e))?;
+            Ok(Attempt::Acquired(Guard::File(lock)))
+        },
+        Ok(false) => {
+            let mut by = String::new();
+            lock.read_to_string(&mut by).ok();
+            Ok(Attempt::Held(match by.trim() {
+                "" => format!("Lock file {} is held", file),
+                by => format!("Lock file {} is held by {}", file, by)
+            }))
+        },
+        Err(err) => Err(format!("Cannot lock file {}, {}", file, err))
+    }
+}
+
+// Lock to prevent concurrent runs, a local file or a Kubernetes Lease
+pub struct Locker {
+    lock: Lock,
+    kubeconfig: Option<String>,
+    in_cluster: bool
+}
+
+impl Locker {
+    pub fn new(lock: Lock, kubeconfig: Option<String>, in_cluster: bool) -> Locker {
+        debug!("Lock: {:?}", lock);
+        Locker {
+            lock,
+            kubeconfig,
+            in_cluster
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<Option<Guard>, String> {
+        // Runs only lock when a lock file or a Lease namespace is configured
+        if self.lock.file.is_none() && self.lock.namespace.is_none() {
+            debug!("No lock configured, running without lock");
+            return Ok(None);
+        }
+        let file = self.lock.file.clone().unwrap_or_default();
+        let holder = holder();
+        // By default the Lease is named as the application
+        let name = self.lock.lease.clone().unwrap_or(APP_NAME.to_string());
+        let api = match &self.lock.namespace {
+            Some(namespace) => match kubernetes::client(self.kubeconfig.as_ref(), self.lock.context.as_ref(), self.in_cluster).await {
+                Some(client) => Some(Api::<Lease>::namespaced(client, namespace)),
+                None => return Err(format!("Kubernetes client cannot be configured to reach Lease {} in {}", &name, namespace))
+            },
+            None => None
+        };
+        // Try to take the lock until the timeout is reached
+        let timeout = self.lock.timeout.unwrap_or(0);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        loop {
+            let attempt = match (&api, &self.lock.namespace) {
+                (Some(api), Some(namespace)) => try_lease(api, namespace, &name, &holder).await?,
+                _ => try_file(&file, &holder)?
+            };
+            match attempt {
+                Attempt::Acquired(guard) => {
+                    info!("Lock acquired by {}", &holder);
+                    return Ok(Some(guard));
+                },
+                Attempt::Held(by) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(format!("{}, another run is in progress (waited {} seconds)", by, timeout));
+                    }
+                    info!("{}, waiting", by);
+                    tokio::time::sleep((deadline - now).min(Duration::from_secs(LOCK_INTERVAL))).await;
+                }
+            }
+        }
+    }
+}
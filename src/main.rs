@@ -4,21 +4,46 @@
 // Distributed under terms of the GNU GPLv3 license.
 //
 
-use clap::{command, Arg, ArgAction, Command};
+use chrono::Local;
+use clap::{command, Arg, ArgAction, ArgMatches, Command};
 use env_logger::{Builder, Env};
-use log::{info, LevelFilter};
-use std::env;
+use log::{error, info, LevelFilter};
+use std::{env, process};
 
-mod atlas;
-mod azure;
-mod config;
-mod kubernetes;
-mod models;
+use cerdito::{daemon, kubernetes, report, state, Cerdito, Config, Filter, APP_NAME, APP_VERSION};
 
-use crate::models::{Atlas, Azure, Config, Kubernetes};
+fn filter_args() -> [Arg; 3] {
+    // Arguments to select a subset of the configured resources
+    [
+        Arg::new("group")
+            .short('g')
+            .long("group")
+            .value_name("GROUP")
+            .action(ArgAction::Append)
+            .help("Only resources of GROUP (can be repeated)"),
+        Arg::new("only")
+            .short('o')
+            .long("only")
+            .value_name("PROVIDER")
+            .action(ArgAction::Append)
+            .help("Only resources of PROVIDER: atlas, azure, aks, databricks or kubernetes (can be repeated)"),
+        Arg::new("resource")
+            .short('r')
+            .long("resource")
+            .value_name("NAME")
+            .action(ArgAction::Append)
+            .help("Only the resource NAME, as name or provider/name (can be repeated)")
+    ]
+}
 
-const APP_NAME: &str = env!("CARGO_PKG_NAME");
-const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+fn filter(matches: &ArgMatches) -> Filter {
+    let values = |id: &str| matches.get_many::<String>(id).map(|v| v.cloned().collect()).unwrap_or_default();
+    Filter {
+        groups: values("group"),
+        providers: values("only"),
+        resources: values("resource")
+    }
+}
 
 #[tokio::main]
 async fn main() {
@@ -35,15 +60,90 @@ async fn main() {
             .short('k')
             .long("kubeconfig")
             .help("Custom kubeconfig file path"))
+        .arg(Arg::new("concurrency")
+            .short('j')
+            .long("concurrency")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .help("Number of resources of each provider processed at the same time"))
+        .arg(Arg::new("lock-timeout")
+            .long("lock-timeout")
+            .value_name("SECONDS")
+            .value_parser(clap::value_parser!(u64))
+            .help("Wait up to SECONDS for the lock held by another run"))
         .arg(Arg::new("verbosity")
              .short('v')
              .long("verbose")
              .action(ArgAction::Count)
              .help("Sets the level of verbosity"))
         .subcommand(Command::new("start")
-            .about("Start all configured elements"))
+            .about("Start all configured elements")
+            .arg(Arg::new("wait")
+                .short('w')
+                .long("wait")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Wait up to SECONDS for Kubernetes workloads to be ready"))
+            .arg(Arg::new("only-previously-stopped")
+                .short('p')
+                .long("only-previously-stopped")
+                .action(ArgAction::SetTrue)
+                .help("Only start the resources that were stopped by cerdito"))
+            .arg(Arg::new("dry-run")
+                .short('n')
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print what would be started without changing anything"))
+            .arg(Arg::new("summary")
+                .short('s')
+                .long("summary")
+                .value_name("FILE")
+                .help("Write a JSON run summary to FILE (use - for stdout)"))
+            .args(filter_args()))
         .subcommand(Command::new("stop")
-            .about("Stop all configured elements"))
+            .about("Stop all configured elements")
+            .arg(Arg::new("dry-run")
+                .short('n')
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print what would be stopped without changing anything"))
+            .arg(Arg::new("summary")
+                .short('s')
+                .long("summary")
+                .value_name("FILE")
+                .help("Write a JSON run summary to FILE (use - for stdout)"))
+            .args(filter_args()))
+        .subcommand(Command::new("status")
+            .about("Show the current state of all configured elements")
+            .arg(Arg::new("json")
+                .short('j')
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print status as JSON"))
+            .args(filter_args()))
+        .subcommand(Command::new("snooze")
+            .about("Keep a resource alive, stop skips it until the given time")
+            .arg(Arg::new("resource")
+                .short('r')
+                .long("resource")
+                .value_name("NAME")
+                .required(true)
+                .help("Resource to keep alive, as name or provider/name"))
+            .arg(Arg::new("until")
+                .short('u')
+                .long("until")
+                .value_name("TIME")
+                .required(true)
+                .help("Time until the resource is kept alive (HH:MM, YYYY-MM-DD HH:MM or RFC 3339)")))
+        .subcommand(Command::new("daemon")
+            .about("Run the configured schedules until stopped"))
+        .subcommand(Command::new("rbac")
+            .about("Prints the Kubernetes RBAC manifest to run in-cluster (with the configured state and lock)")
+            .arg(Arg::new("namespace")
+                .short('n')
+                .long("namespace")
+                .default_value("default")
+                .help("Namespace of the service account")))
         .subcommand(Command::new("version")
             .about("Prints version information"))
         .get_matches();
@@ -53,7 +153,7 @@ async fn main() {
         0 => Builder::from_env(Env::default().filter_or(format!("{}_LOGLEVEL", APP_NAME.to_uppercase()), "off")).init(),
         1 => Builder::new().filter_level(LevelFilter::Info).init(),
         2 => Builder::new().filter_level(LevelFilter::Debug).init(),
-        3 | _ => Builder::new().filter_level(LevelFilter::Trace).init()
+        _ => Builder::new().filter_level(LevelFilter::Trace).init()
     };
     info!("Log level: {:?}", log::max_level());
 
@@ -62,43 +162,101 @@ async fn main() {
 
     // Read config file
     let config = Config::figment(config_file);
-    let config: Config = match config.extract() {
+    let mut config: Config = match config.extract() {
         Ok(config) => config,
         Err(err) => panic!("Error in config file: {}", err)
     };
 
     // Get Atlas keys from config file or environment
-    let atlas_public_key = env::var("MONGODB_ATLAS_PUBLIC_KEY").ok().or(config.atlas.public_key);
-    let atlas_private_key = env::var("MONGODB_ATLAS_PRIVATE_KEY").ok().or(config.atlas.private_key);
-
-    // Configure Atlas client
-    let atlas_client = Atlas::new(atlas_public_key, atlas_private_key, config.atlas.clusters);
+    config.atlas.public_key = env::var("MONGODB_ATLAS_PUBLIC_KEY").ok().or(config.atlas.public_key);
+    config.atlas.private_key = env::var("MONGODB_ATLAS_PRIVATE_KEY").ok().or(config.atlas.private_key);
 
     // Get Azure credentials from config file or environment
-    let azure_tenant_id = env::var("AZURE_TENANT_ID").ok().or(config.azure.tenant_id);
-    let azure_client_id = env::var("AZURE_CLIENT_ID").ok().or(config.azure.client_id);
-    let azure_client_secret = env::var("AZURE_CLIENT_SECRET").ok().or(config.azure.client_secret);
-
-    // Configure Azure client
-    let azure_client = Azure::new(azure_tenant_id, azure_client_id, azure_client_secret, config.azure.aks, config.azure.databricks);
+    config.azure.tenant_id = env::var("AZURE_TENANT_ID").ok().or(config.azure.tenant_id);
+    config.azure.client_id = env::var("AZURE_CLIENT_ID").ok().or(config.azure.client_id);
+    config.azure.client_secret = env::var("AZURE_CLIENT_SECRET").ok().or(config.azure.client_secret);
 
     // Get Kubernetes config file location from params, environment or config
-    let kubeconfig = matches.get_one::<String>("kubeconfig").cloned().or(env::var("KUBECONFIG").ok().or(config.kubernetes.kubeconfig));
+    config.kubernetes.kubeconfig = matches.get_one::<String>("kubeconfig").cloned().or(env::var("KUBECONFIG").ok().or(config.kubernetes.kubeconfig));
+
+    // Get Kubernetes wait timeout from params or config
+    config.kubernetes.wait = matches.subcommand_matches("start").and_then(|m| m.get_one::<u64>("wait").copied()).or(config.kubernetes.wait);
 
-    // Configure Kubernetes client
-    let kubernetes_client = Kubernetes::new(kubeconfig, config.kubernetes.projects);
+    // Get concurrency from params or config of each provider
+    if let Some(concurrency) = matches.get_one::<usize>("concurrency").copied() {
+        config.atlas.concurrency = Some(concurrency);
+        config.azure.concurrency = Some(concurrency);
+        config.kubernetes.concurrency = Some(concurrency);
+    }
+
+    // Get lock timeout from params or config
+    config.lock.timeout = matches.get_one::<u64>("lock-timeout").copied().or(config.lock.timeout);
+
+    // Get the schedules for daemon mode
+    let schedules = config.schedules.take().unwrap_or_default();
+    let calendars = config.calendars.take().unwrap_or_default();
+
+    // Get the namespaces of the state and the lock for the RBAC manifest
+    let storage_namespace = config.storage.namespace.clone();
+    let lock_namespace = config.lock.namespace.clone();
+
+    // Configure all providers
+    let cerdito = Cerdito::new(config);
 
     match matches.subcommand() {
         Some(("version", _)) => println!("{} {}", APP_NAME, APP_VERSION),
-        Some(("start", _)) => {
-            atlas_client.pause(false).await;
-            azure_client.pause(false).await;
-            kubernetes_client.pause(false).await;
+        Some(("rbac", matches)) => print!("{}", kubernetes::rbac(APP_NAME, matches.get_one::<String>("namespace").unwrap(), storage_namespace.as_deref(), lock_namespace.as_deref())),
+        Some((action @ ("start" | "stop"), matches)) => {
+            let dry_run = matches.get_flag("dry-run");
+            let summary = match action {
+                "start" if matches.get_flag("only-previously-stopped") => cerdito.start_previously_stopped(dry_run, &filter(matches)).await,
+                "start" => cerdito.start(dry_run, &filter(matches)).await,
+                _ => cerdito.stop(dry_run, &filter(matches)).await
+            };
+            summary.log();
+            // Print the plan unless the summary goes to stdout
+            if dry_run && matches.get_one::<String>("summary").map(String::as_str) != Some("-") {
+                summary.print_plan();
+            }
+            if let Some(path) = matches.get_one::<String>("summary") {
+                if let Err(err) = summary.write(path) {
+                    error!("There has been a problem writing summary to {}, {}", path, err);
+                }
+            }
+            // Exit with error if something has failed
+            if !summary.success {
+                process::exit(1);
+            }
+        },
+        Some(("snooze", matches)) => {
+            let resource = matches.get_one::<String>("resource").unwrap();
+            let result = match state::parse_until(matches.get_one::<String>("until").unwrap(), Local::now()) {
+                Ok(until) => cerdito.snooze(resource, until).await.map(|ids| (ids, until)),
+                Err(message) => Err(message)
+            };
+            match result {
+                Ok((ids, until)) => for id in ids {
+                    println!("Snoozed {} until {}", id, until.with_timezone(&Local));
+                },
+                Err(message) => {
+                    eprintln!("Error: {}", &message);
+                    process::exit(1);
+                }
+            }
+        },
+        Some(("daemon", _)) => {
+            if let Err(message) = daemon::run(&cerdito, &schedules, &calendars).await {
+                eprintln!("Error: {}", &message);
+                process::exit(1);
+            }
         },
-        Some(("stop", _)) => {
-            kubernetes_client.pause(true).await;
-            azure_client.pause(true).await;
-            atlas_client.pause(true).await;
+        Some(("status", matches)) => {
+            let statuses = cerdito.status(&filter(matches)).await;
+            if matches.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&statuses).expect("Status serialization"));
+            } else {
+                report::print_table(&statuses);
+            }
         },
         _ => unreachable!()
     }
@@ -6,11 +6,17 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::provider::Action;
+
 // Atlas clusters definition
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AtlasCluster {
     pub name: String,
-    pub group_id: String
+    pub group_id: String,
+    #[serde(default = "default_depends_on")]
+    pub depends_on: Vec<String>,
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>
 }
 
 // Atlas definition
@@ -18,15 +24,21 @@ pub struct AtlasCluster {
 pub struct Atlas {
     pub public_key: Option<String>,
     pub private_key: Option<String>,
+    pub concurrency: Option<usize>,
     pub clusters: Option<Vec<AtlasCluster>>
 }
 
 // AKS definition
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AKS {
     pub subscription_id: String,
     pub resource_group_name: String,
-    pub resource_name: String
+    pub resource_name: String,
+    #[serde(default = "default_depends_on")]
+    pub depends_on: Vec<String>,
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>
 }
 
 // Databricks definition
@@ -37,7 +49,11 @@ pub struct Databricks {
     pub all_jobs: bool,
     #[serde(default = "default_jobs")]
     pub jobs: Vec<String>,
-    pub delete: Option<Vec<String>>
+    pub delete: Option<Vec<String>>,
+    #[serde(default = "default_depends_on")]
+    pub depends_on: Vec<String>,
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>
 }
 
 // Azure definition
@@ -46,31 +62,153 @@ pub struct Azure {
     pub tenant_id: Option<String>,
     pub client_id: Option<String>,
     pub client_secret: Option<String>,
+    pub concurrency: Option<usize>,
     pub aks: Option<Vec<AKS>>,
     pub databricks: Option<Vec<Databricks>>
 }
 
+// Kubernetes cluster definition
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KubernetesCluster {
+    pub name: String,
+    pub kubeconfig: Option<String>,
+    pub context: Option<String>,
+    #[serde(default = "default_in_cluster")]
+    pub in_cluster: bool
+}
+
 // Kubernetes project definition
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct KubernetesProject {
-    pub namespace: String,
-    pub deployments: Vec<String>
+    pub cluster: Option<String>,
+    pub namespace: Option<String>,
+    pub namespace_selector: Option<String>,
+    pub namespace_pattern: Option<String>,
+    #[serde(default = "default_workloads")]
+    pub deployments: Vec<String>,
+    #[serde(default = "default_workloads")]
+    pub statefulsets: Vec<String>,
+    pub selector: Option<String>,
+    #[serde(default = "default_all")]
+    pub all: bool,
+    #[serde(default = "default_workloads")]
+    pub cronjobs: Vec<String>,
+    #[serde(default = "default_all_cronjobs")]
+    pub all_cronjobs: bool,
+    #[serde(default = "default_workloads")]
+    pub scaledobjects: Vec<String>,
+    #[serde(default = "default_all_scaledobjects")]
+    pub all_scaledobjects: bool,
+    #[serde(default = "default_workloads")]
+    pub flux_kustomizations: Vec<String>,
+    #[serde(default = "default_workloads")]
+    pub flux_helmreleases: Vec<String>,
+    pub flux_namespace: Option<String>,
+    #[serde(default = "default_workloads")]
+    pub argocd_applications: Vec<String>,
+    #[serde(default = "default_argocd_namespace")]
+    pub argocd_namespace: String,
+    #[serde(default = "default_replicas")]
+    pub default_replicas: i32,
+    #[serde(default = "default_depends_on")]
+    pub depends_on: Vec<String>,
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>
 }
 
 // Kubernetes definition
 #[derive(Deserialize, Serialize)]
 pub struct Kubernetes {
     pub kubeconfig: Option<String>,
+    pub in_cluster: Option<bool>,
+    pub wait: Option<u64>,
+    pub concurrency: Option<usize>,
+    pub clusters: Option<Vec<KubernetesCluster>>,
     pub projects: Option<Vec<KubernetesProject>>
 }
 
+// Scheduled action definition
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Schedule {
+    pub action: Action,
+    pub cron: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>,
+    #[serde(default = "default_calendars")]
+    pub calendars: Vec<String>
+}
+
+// What to do with scheduled runs on the dates of a calendar
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarMode {
+    Skip,
+    Force
+}
+
+// Calendar date range definition (to is inclusive)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DateRange {
+    pub from: String,
+    pub to: Option<String>
+}
+
+// Calendar definition
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Calendar {
+    pub name: String,
+    #[serde(default = "default_calendar_mode")]
+    pub mode: CalendarMode,
+    pub ics: Option<String>,
+    #[serde(default = "default_dates")]
+    pub dates: Vec<DateRange>
+}
+
+// Storage of cerdito state definition
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Storage {
+    pub file: Option<String>,
+    pub namespace: Option<String>,
+    pub configmap: Option<String>,
+    pub context: Option<String>
+}
+
+// Lock held while cerdito starts or stops definition
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Lock {
+    pub file: Option<String>,
+    pub namespace: Option<String>,
+    pub lease: Option<String>,
+    pub context: Option<String>,
+    pub timeout: Option<u64>
+}
+
 // cerdito main configuration
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub atlas: Atlas,
     pub azure: Azure,
-    pub kubernetes: Kubernetes
+    pub kubernetes: Kubernetes,
+    pub schedules: Option<Vec<Schedule>>,
+    pub calendars: Option<Vec<Calendar>>,
+    pub storage: Storage,
+    pub lock: Lock
 }
 
 fn default_all_jobs() -> bool { false }
 fn default_jobs() -> Vec<String> { std::vec::Vec::new() }
+fn default_replicas() -> i32 { 1 }
+fn default_in_cluster() -> bool { false }
+fn default_workloads() -> Vec<String> { std::vec::Vec::new() }
+fn default_all_cronjobs() -> bool { false }
+fn default_all() -> bool { false }
+fn default_all_scaledobjects() -> bool { false }
+fn default_argocd_namespace() -> String { String::from("argocd") }
+fn default_depends_on() -> Vec<String> { std::vec::Vec::new() }
+fn default_groups() -> Vec<String> { std::vec::Vec::new() }
+fn default_timezone() -> String { String::from("UTC") }
+fn default_calendars() -> Vec<String> { std::vec::Vec::new() }
+fn default_calendar_mode() -> CalendarMode { CalendarMode::Skip }
+fn default_dates() -> Vec<DateRange> { std::vec::Vec::new() }
@@ -0,0 +1,370 @@
+//
+// provider.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+use async_trait::async_trait;
+use futures::future;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::{HashMap, HashSet}, fmt::{self, Display}, time::Instant};
+
+use crate::report::{Outcome, Status};
+
+// Number of resources of a provider processed at the same time if not configured
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+pub fn concurrency(concurrency: Option<usize>) -> usize {
+    // At least one resource must be processed at a time
+    concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1)
+}
+
+pub fn opted_out(value: Option<&String>) -> bool {
+    // Resources opt out of being stopped setting their skip tag or annotation to true
+    value.is_some_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+// Action to perform over the configured resources
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Start,
+    Stop
+}
+
+impl Action {
+    pub fn gerund(&self) -> &'static str {
+        match self {
+            Action::Start => "Starting",
+            Action::Stop => "Stopping"
+        }
+    }
+
+    pub fn participle(&self) -> &'static str {
+        match self {
+            Action::Start => "started",
+            Action::Stop => "stopped"
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Start => "start",
+            Action::Stop => "stop"
+        };
+        write!(f, "{}", action)
+    }
+}
+
+// Configured resource, identified as "provider/name" so it can be referenced from depends_on
+#[derive(Clone, Debug)]
+pub struct Resource {
+    pub id: String,
+    // Kind, location and name used to report the resource
+    pub kind: String,
+    pub location: String,
+    pub name: String,
+    pub depends_on: Vec<String>,
+    pub groups: Vec<String>
+}
+
+impl Resource {
+    pub fn new(id: String, kind: &str, location: &str, name: &str, depends_on: &[String], groups: &[String]) -> Resource {
+        Resource {
+            id,
+            kind: kind.to_string(),
+            location: location.to_string(),
+            name: name.to_string(),
+            depends_on: depends_on.to_vec(),
+            groups: groups.to_vec()
+        }
+    }
+}
+
+// Filter to select a subset of the configured resources, empty lists match everything
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub groups: Vec<String>,
+    pub providers: Vec<String>,
+    pub resources: Vec<String>
+}
+
+impl Filter {
+    pub fn matches(&self, provider: &str, resource: &Resource) -> bool {
+        // Resource type (atlas, aks, databricks, kubernetes) and name from id
+        let (kind, name) = resource.id.split_once('/').unwrap_or_default();
+        (self.groups.is_empty() || resource.groups.iter().any(|g| self.groups.contains(g)))
+            && (self.providers.is_empty() || self.providers.iter().any(|p| p == provider || p == kind))
+            && (self.resources.is_empty() || self.resources.iter().any(|r| *r == resource.id || r == name))
+    }
+}
+
+// Backend that can stop and start a set of configured resources
+#[async_trait]
+pub trait Provider: Send + Sync {
+    // Name of provider used to filter its resources
+    fn name(&self) -> &'static str;
+
+    // List of configured resources
+    fn resources(&self) -> Vec<Resource>;
+
+    // Perform the action over the selected resources, when dry run only print what would be done
+    async fn execute(&self, action: Action, dry_run: bool, selection: &HashSet<String>) -> Vec<Outcome>;
+
+    // Get the current state of the selected resources
+    async fn status(&self, selection: &HashSet<String>) -> Vec<Status>;
+
+    async fn plan(&self, action: Action, selection: &HashSet<String>) -> Vec<Outcome> {
+        self.execute(action, true, selection).await
+    }
+
+    async fn stop(&self, selection: &HashSet<String>) -> Vec<Outcome> {
+        self.execute(Action::Stop, false, selection).await
+    }
+
+    async fn start(&self, selection: &HashSet<String>) -> Vec<Outcome> {
+        self.execute(Action::Start, false, selection).await
+    }
+
+    // Start only what cerdito stopped inside the selected resources, stopped has the items
+    // recorded for each resource (by default the whole resources are started)
+    async fn restore(&self, dry_run: bool, selection: &HashSet<String>, _stopped: &HashMap<String, Vec<String>>) -> Vec<Outcome> {
+        self.execute(Action::Start, dry_run, selection).await
+    }
+}
+
+// Dependency of a resource, an explicit one goes in a later stage and the implicit one on the
+// previous providers can share the stage (providers go in order inside a stage)
+struct Dependency {
+    id: String,
+    later: bool
+}
+
+fn stage(id: &str, dependencies: &HashMap<String, Vec<Dependency>>, stages: &mut HashMap<String, usize>, visiting: &mut Vec<String>) -> Result<usize, String> {
+    if let Some(stage) = stages.get(id) {
+        return Ok(*stage);
+    }
+    if visiting.iter().any(|v| v == id) {
+        return Err(format!("Dependency cycle {} -> {}", visiting.join(" -> "), id));
+    }
+    let depends_on = match dependencies.get(id) {
+        Some(depends_on) => depends_on,
+        None => return Err(format!("Resource {} referenced in depends_on of {} is not defined", id, visiting.last().map(String::as_str).unwrap_or_default()))
+    };
+    // A resource goes in the stage after the last of its dependencies
+    visiting.push(id.to_string());
+    let mut resource_stage = 0;
+    for dependency in depends_on {
+        resource_stage = resource_stage.max(stage(&dependency.id, dependencies, stages, visiting)? + usize::from(dependency.later));
+    }
+    visiting.pop();
+    stages.insert(id.to_string(), resource_stage);
+    Ok(resource_stage)
+}
+
+fn stages(providers: &[&str], resources: &[Vec<Resource>]) -> Result<HashMap<String, usize>, String> {
+    // Merge the dependencies of resources that share id
+    let mut dependencies: HashMap<String, Vec<Dependency>> = HashMap::new();
+    for resource in resources.iter().flatten() {
+        dependencies.entry(resource.id.clone()).or_default().extend(resource.depends_on.iter().map(|d| Dependency { id: d.clone(), later: true }));
+    }
+    // Every resource also goes after all resources of the previous providers, so a depends_on
+    // never leaves a resource before the ones of the previous providers
+    for index in 1..providers.len() {
+        let previous = format!("resources of {}", providers[index - 1]);
+        let mut after: Vec<Dependency> = resources[index - 1].iter().map(|r| Dependency { id: r.id.clone(), later: false }).collect();
+        if index > 1 {
+            after.push(Dependency { id: format!("resources of {}", providers[index - 2]), later: false });
+        }
+        dependencies.insert(previous.clone(), after);
+        for resource in &resources[index] {
+            dependencies.entry(resource.id.clone()).or_default().push(Dependency { id: previous.clone(), later: false });
+        }
+    }
+    let mut stages = HashMap::new();
+    let mut ids: Vec<&String> = resources.iter().flatten().map(|r| &r.id).collect();
+    ids.sort();
+    ids.dedup();
+    for id in ids {
+        stage(id, &dependencies, &mut stages, &mut Vec::new())?;
+    }
+    // Only the stages of resources
+    stages.retain(|id, _| resources.iter().flatten().any(|r| &r.id == id));
+    Ok(stages)
+}
+
+fn select(provider: &dyn Provider, resources: &[Resource], filter: &Filter) -> HashSet<String> {
+    resources.iter().filter(|r| filter.matches(provider.name(), r)).map(|r| r.id.clone()).collect()
+}
+
+pub async fn run(providers: &[Box<dyn Provider>], action: Action, dry_run: bool, filter: &Filter, skipped: &HashMap<String, String>, stopped: Option<&HashMap<String, Vec<String>>>) -> Vec<Outcome> {
+    let mut outcomes = Vec::new();
+    let start = Instant::now();
+    // Compute the stage of every resource from its dependencies
+    let resources: Vec<Vec<Resource>> = providers.iter().map(|p| p.resources()).collect();
+    let names: Vec<&str> = providers.iter().map(|p| p.name()).collect();
+    let stages = match stages(&names, &resources) {
+        Ok(stages) => stages,
+        Err(message) => {
+            error!("{}", &message);
+            outcomes.push(Outcome::failed("cerdito", "dependencies", "*", "*", start, &message));
+            return outcomes;
+        }
+    };
+    // Only the resources that match the filter, dependencies of other resources are still
+    // honoured to compute the order
+    let selections: Vec<HashSet<String>> = providers.iter().zip(&resources).map(|(p, r)| select(p.as_ref(), r, filter)).collect();
+    if selections.iter().all(|s| s.is_empty()) {
+        warn!("There are no resources matching the given filters");
+    }
+    let count = stages.values().max().map_or(0, |s| s + 1);
+    // Stages and providers inside them are given in start order, on stop they go in reverse order
+    let mut steps: Vec<(usize, usize)> = (0..count).flat_map(|s| (0..providers.len()).map(move |p| (s, p))).collect();
+    if action == Action::Stop {
+        steps.reverse();
+    }
+    for (stage, index) in steps {
+        let provider = &providers[index];
+        let mut selection: HashSet<String> = selections[index].iter().filter(|id| stages[*id] == stage).cloned().collect();
+        // Resources that must be skipped do not reach the provider
+        for resource in resources[index].iter().filter(|r| skipped.contains_key(&r.id)) {
+            if selection.remove(&resource.id) {
+                let reason = &skipped[&resource.id];
+                info!("Skipping {}, {}", &resource.id, reason);
+                outcomes.push(Outcome::skipped(provider.name(), &resource.kind, &resource.location, &resource.name, Instant::now(), reason).of(&resource.id));
+            }
+        }
+        if selection.is_empty() {
+            continue;
+        }
+        debug!("Trying to {} {} resources of stage {} of {}", action, selection.len(), stage + 1, count);
+        let provider_outcomes = match (action, dry_run, stopped) {
+            (Action::Start, _, Some(stopped)) => provider.restore(dry_run, &selection, stopped).await,
+            (_, true, _) => provider.plan(action, &selection).await,
+            (Action::Start, false, _) => provider.start(&selection).await,
+            (Action::Stop, false, _) => provider.stop(&selection).await
+        };
+        outcomes.extend(provider_outcomes);
+    }
+    outcomes
+}
+
+pub async fn status(providers: &[Box<dyn Provider>], filter: &Filter) -> Vec<Status> {
+    let selections: Vec<HashSet<String>> = providers.iter().map(|p| select(p.as_ref(), &p.resources(), filter)).collect();
+    // Providers are independent when getting the status, so ask all of them at the same time
+    let requests = providers.iter().zip(&selections).filter(|(_, s)| !s.is_empty()).map(|(p, s)| p.status(s));
+    future::join_all(requests).await.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(id: &str, depends_on: &[&str]) -> Resource {
+        let depends_on: Vec<String> = depends_on.iter().map(|d| d.to_string()).collect();
+        Resource::new(id.to_string(), "cluster", "test", id, &depends_on, &[])
+    }
+
+    fn filter(groups: &[&str], providers: &[&str], resources: &[&str]) -> Filter {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        Filter {
+            groups: strings(groups),
+            providers: strings(providers),
+            resources: strings(resources)
+        }
+    }
+
+    #[test]
+    fn stages_follow_dependencies() {
+        let resources = vec![
+            vec![resource("atlas/db", &[])],
+            vec![resource("kubernetes/api", &["atlas/db"]), resource("kubernetes/web", &["kubernetes/api", "atlas/db"])]
+        ];
+        let stages = stages(&["atlas", "kubernetes"], &resources).unwrap();
+        assert_eq!(stages["atlas/db"], 0);
+        assert_eq!(stages["kubernetes/api"], 1);
+        assert_eq!(stages["kubernetes/web"], 2);
+    }
+
+    #[test]
+    fn stages_merge_dependencies_of_shared_ids() {
+        let resources = vec![
+            vec![resource("atlas/db", &[]), resource("aks/aks", &[])],
+            vec![resource("kubernetes/app", &["atlas/db"]), resource("kubernetes/app", &["aks/aks"])]
+        ];
+        assert_eq!(stages(&["azure", "kubernetes"], &resources).unwrap()["kubernetes/app"], 1);
+    }
+
+    #[test]
+    fn stages_reject_cycles() {
+        let resources = vec![vec![resource("atlas/a", &["atlas/b"]), resource("atlas/b", &["atlas/c"]), resource("atlas/c", &["atlas/a"])]];
+        assert_eq!(stages(&["atlas"], &resources).unwrap_err(), "Dependency cycle atlas/a -> atlas/b -> atlas/c -> atlas/a");
+        let resources = vec![vec![resource("atlas/a", &["atlas/a"])]];
+        assert_eq!(stages(&["atlas"], &resources).unwrap_err(), "Dependency cycle atlas/a -> atlas/a");
+    }
+
+    #[test]
+    fn stages_keep_the_provider_order() {
+        // Kubernetes projects without depends_on still go after the AKS they run on
+        let resources = vec![
+            vec![resource("atlas/db", &[])],
+            vec![resource("aks/aks", &["atlas/db"])],
+            vec![resource("kubernetes/app", &[])]
+        ];
+        let order = stages(&["atlas", "azure", "kubernetes"], &resources).unwrap();
+        assert_eq!(order["atlas/db"], 0);
+        assert_eq!(order["aks/aks"], 1);
+        assert_eq!(order["kubernetes/app"], 1);
+        // Also when the previous provider has no resources
+        let resources = vec![vec![resource("atlas/db", &[]), resource("atlas/dw", &["atlas/db"])], vec![], vec![resource("kubernetes/app", &[])]];
+        assert_eq!(stages(&["atlas", "azure", "kubernetes"], &resources).unwrap()["kubernetes/app"], 1);
+    }
+
+    #[test]
+    fn stages_reject_dependencies_against_the_provider_order() {
+        let resources = vec![vec![resource("atlas/db", &["kubernetes/app"])], vec![], vec![resource("kubernetes/app", &[])]];
+        assert_eq!(stages(&["atlas", "azure", "kubernetes"], &resources).unwrap_err(), "Dependency cycle atlas/db -> kubernetes/app -> resources of azure -> resources of atlas -> atlas/db");
+    }
+
+    #[test]
+    fn stages_reject_undefined_references() {
+        let resources = vec![vec![resource("kubernetes/app", &["atlas/db"])]];
+        assert_eq!(stages(&["kubernetes"], &resources).unwrap_err(), "Resource atlas/db referenced in depends_on of kubernetes/app is not defined");
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(Filter::default().matches("azure", &resource("databricks/jobs", &[])));
+    }
+
+    #[test]
+    fn filter_matches_name_or_id() {
+        let db = resource("atlas/db", &[]);
+        assert!(filter(&[], &[], &["db"]).matches("atlas", &db));
+        assert!(filter(&[], &[], &["atlas/db"]).matches("atlas", &db));
+        assert!(!filter(&[], &[], &["aks/db"]).matches("atlas", &db));
+        assert!(!filter(&[], &[], &["atlas"]).matches("atlas", &db));
+        assert!(!filter(&[], &[], &["d"]).matches("atlas", &db));
+    }
+
+    #[test]
+    fn filter_matches_provider_or_resource_type() {
+        let jobs = resource("databricks/jobs", &[]);
+        assert!(filter(&[], &["azure"], &[]).matches("azure", &jobs));
+        assert!(filter(&[], &["databricks"], &[]).matches("azure", &jobs));
+        assert!(!filter(&[], &["aks"], &[]).matches("azure", &jobs));
+    }
+
+    #[test]
+    fn filter_requires_every_criterion() {
+        let mut db = resource("atlas/db", &[]);
+        db.groups = vec![String::from("staging")];
+        assert!(filter(&["staging", "dev"], &["atlas"], &["db"]).matches("atlas", &db));
+        assert!(!filter(&["dev"], &["atlas"], &["db"]).matches("atlas", &db));
+        assert!(!filter(&["staging"], &["kubernetes"], &["db"]).matches("atlas", &db));
+        assert!(!filter(&["staging"], &["atlas"], &["api"]).matches("atlas", &db));
+    }
+}
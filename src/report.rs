@@ -0,0 +1,181 @@
+//
+// report.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+use log::info;
+use serde::Serialize;
+use std::{fs, io, time::Instant};
+
+// Current state of a configured resource
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub provider: String,
+    pub kind: String,
+    pub location: String,
+    pub name: String,
+    pub state: String
+}
+
+impl Status {
+    pub fn new(provider: &str, kind: &str, location: &str, name: &str, state: &str) -> Status {
+        Status {
+            provider: provider.to_string(),
+            kind: kind.to_string(),
+            location: location.to_string(),
+            name: name.to_string(),
+            state: state.to_string()
+        }
+    }
+}
+
+pub fn print_table(statuses: &[Status]) {
+    let header = ["PROVIDER", "KIND", "LOCATION", "NAME", "STATE"];
+    let rows: Vec<[&str; 5]> = statuses.iter()
+        .map(|s| [s.provider.as_str(), s.kind.as_str(), s.location.as_str(), s.name.as_str(), s.state.as_str()])
+        .collect();
+    // Compute the width of each column
+    let mut widths = header.map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+// Result of the action over a resource
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Done,
+    Skipped,
+    Planned,
+    Failed
+}
+
+// Outcome of the action over a configured resource
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub provider: String,
+    // Id of the configured resource (if the outcome belongs to one)
+    pub resource: Option<String>,
+    // Id of the item inside the resource when it is not the object itself (as Databricks job ids)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item: Option<String>,
+    pub kind: String,
+    pub location: String,
+    pub name: String,
+    pub state: State,
+    pub error: Option<String>,
+    // Why the resource has been skipped or what is planned for it (if known)
+    pub reason: Option<String>,
+    // Duration in seconds
+    pub duration: f64
+}
+
+impl Outcome {
+    pub fn new(provider: &str, kind: &str, location: &str, name: &str, state: State, start: Instant) -> Outcome {
+        Outcome {
+            provider: provider.to_string(),
+            resource: None,
+            item: None,
+            kind: kind.to_string(),
+            location: location.to_string(),
+            name: name.to_string(),
+            state,
+            error: None,
+            reason: None,
+            duration: start.elapsed().as_secs_f64()
+        }
+    }
+
+    pub fn failed(provider: &str, kind: &str, location: &str, name: &str, start: Instant, error: &str) -> Outcome {
+        Outcome {
+            error: Some(error.to_string()),
+            ..Outcome::new(provider, kind, location, name, State::Failed, start)
+        }
+    }
+
+    pub fn skipped(provider: &str, kind: &str, location: &str, name: &str, start: Instant, reason: &str) -> Outcome {
+        Outcome {
+            reason: Some(reason.to_string()),
+            ..Outcome::new(provider, kind, location, name, State::Skipped, start)
+        }
+    }
+
+    pub fn planned(provider: &str, kind: &str, location: &str, name: &str, plan: &str) -> Outcome {
+        Outcome {
+            reason: Some(plan.to_string()),
+            ..Outcome::new(provider, kind, location, name, State::Planned, Instant::now())
+        }
+    }
+
+    pub fn of(self, resource: &str) -> Outcome {
+        Outcome {
+            resource: Some(resource.to_string()),
+            ..self
+        }
+    }
+
+    pub fn item(self, item: &str) -> Outcome {
+        Outcome {
+            item: Some(item.to_string()),
+            ..self
+        }
+    }
+}
+
+// Summary of a whole run
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub action: String,
+    pub success: bool,
+    pub done: usize,
+    pub skipped: usize,
+    pub planned: usize,
+    pub failed: usize,
+    pub outcomes: Vec<Outcome>
+}
+
+impl Summary {
+    pub fn new(action: &str, outcomes: Vec<Outcome>) -> Summary {
+        let count = |state| outcomes.iter().filter(|o| o.state == state).count();
+        let (done, skipped, planned, failed) = (count(State::Done), count(State::Skipped), count(State::Planned), count(State::Failed));
+        Summary {
+            action: action.to_string(),
+            success: failed == 0,
+            done,
+            skipped,
+            planned,
+            failed,
+            outcomes
+        }
+    }
+
+    pub fn log(&self) {
+        info!("Run summary: {} done, {} skipped, {} planned, {} failed", self.done, self.skipped, self.planned, self.failed);
+    }
+
+    pub fn print_plan(&self) {
+        // Tell what a dry run would change
+        for outcome in self.outcomes.iter().filter(|o| o.state == State::Planned) {
+            println!("Would {} {} {} {} in {}", outcome.reason.as_deref().unwrap_or("change"), &outcome.provider, &outcome.kind, &outcome.name, &outcome.location);
+        }
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        // Write summary as JSON to given file or to stdout with "-"
+        let json = serde_json::to_string_pretty(self)?;
+        if path == "-" {
+            println!("{}", json);
+            Ok(())
+        } else {
+            fs::write(path, json + "\n")
+        }
+    }
+}
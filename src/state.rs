@@ -0,0 +1,195 @@
+//
+// state.rs
+// Copyright (C) 2024 Óscar García Amor <ogarcia@connectical.com>
+// Distributed under terms of the GNU GPLv3 license.
+//
+
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{Api, Patch, PatchParams};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::ErrorKind};
+
+use crate::{kubernetes, models::Storage, APP_NAME};
+
+// Key of the ConfigMap data where the state is saved
+const CONFIGMAP_KEY: &str = "state.json";
+
+// Resource that must not be stopped until a given time
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Snooze {
+    pub resource: String,
+    pub until: DateTime<Utc>
+}
+
+// Resource stopped by cerdito and not started since then
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Stopped {
+    pub resource: String,
+    pub at: DateTime<Utc>,
+    // Items of the resource stopped by cerdito (as Databricks job ids)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<String>
+}
+
+// State saved by cerdito between runs
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SavedState {
+    #[serde(default)]
+    pub snoozes: Vec<Snooze>,
+    #[serde(default)]
+    pub stopped: Vec<Stopped>
+}
+
+impl SavedState {
+    pub fn snoozed(&self, resource: &str, now: DateTime<Utc>) -> Option<&Snooze> {
+        self.snoozes.iter().filter(|s| s.resource == resource && s.until > now).max_by_key(|s| s.until)
+    }
+}
+
+// Place where the state is saved, a local file or a ConfigMap
+pub struct Store {
+    storage: Storage,
+    kubeconfig: Option<String>,
+    in_cluster: bool
+}
+
+impl Store {
+    pub fn new(storage: Storage, kubeconfig: Option<String>, in_cluster: bool) -> Store {
+        debug!("State storage: {:?}", storage);
+        Store {
+            storage,
+            kubeconfig,
+            in_cluster
+        }
+    }
+
+    fn file(&self) -> String {
+        // By default the state is saved in current directory
+        self.storage.file.clone().unwrap_or(format!("{}.state.json", APP_NAME))
+    }
+
+    async fn configmap(&self, namespace: &str) -> Result<(Api<ConfigMap>, String), String> {
+        // By default the ConfigMap is named as the application
+        let name = self.storage.configmap.clone().unwrap_or(APP_NAME.to_string());
+        match kubernetes::client(self.kubeconfig.as_ref(), self.storage.context.as_ref(), self.in_cluster).await {
+            Some(client) => Ok((Api::namespaced(client, namespace), name)),
+            None => Err(format!("Kubernetes client cannot be configured to reach state ConfigMap {} in {}", &name, namespace))
+        }
+    }
+
+    pub async fn load(&self) -> Result<SavedState, String> {
+        let content = match &self.storage.namespace {
+            Some(namespace) => {
+                let (api, name) = self.configmap(namespace).await?;
+                debug!("State ConfigMap location: {}/{}", namespace, &name);
+                match api.get_opt(&name).await {
+                    Ok(configmap) => configmap.and_then(|c| c.data).and_then(|mut d| d.remove(CONFIGMAP_KEY)),
+                    Err(err) => return Err(format!("Cannot read state ConfigMap {} in {}, {}", &name, namespace, err))
+                }
+            },
+            None => {
+                let file = self.file();
+                debug!("State file location: {}", &file);
+                match fs::read_to_string(&file) {
+                    Ok(content) => Some(content),
+                    Err(err) if err.kind() == ErrorKind::NotFound => None,
+                    Err(err) => return Err(format!("Cannot read state file {}, {}", &file, err))
+                }
+            }
+        };
+        // There is no state until something is saved
+        match content {
+            Some(content) => serde_json::from_str(&content).map_err(|e| format!("Invalid state, {}", e)),
+            None => Ok(SavedState::default())
+        }
+    }
+
+    pub async fn save(&self, state: &SavedState) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(state).map_err(|e| format!("Cannot serialize state, {}", e))? + "\n";
+        match &self.storage.namespace {
+            Some(namespace) => {
+                let (api, name) = self.configmap(namespace).await?;
+                // Apply the ConfigMap, it is created the first time
+                let configmap = serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "metadata": {"name": &name},
+                    "data": {CONFIGMAP_KEY: content}
+                });
+                api.patch(&name, &PatchParams::apply(APP_NAME).force(), &Patch::Apply(configmap)).await
+                    .map(|_| ())
+                    .map_err(|e| format!("Cannot write state ConfigMap {} in {}, {}", &name, namespace, e))
+            },
+            None => {
+                let file = self.file();
+                fs::write(&file, content).map_err(|e| format!("Cannot write state file {}, {}", &file, e))
+            }
+        }
+    }
+}
+
+pub fn parse_until(value: &str, now: DateTime<Local>) -> Result<DateTime<Utc>, String> {
+    // A time alone is the next time it happens in local time
+    if let Ok(time) = NaiveTime::parse_from_str(value, "%H:%M") {
+        let mut until = now.date_naive().and_time(time);
+        if until <= now.naive_local() {
+            until += TimeDelta::days(1);
+        }
+        return until.and_local_timezone(Local).earliest().map(|u| u.with_timezone(&Utc)).ok_or_else(|| format!("Time {} does not exist in local time zone", value));
+    }
+    // Local date and time or a full RFC 3339 date time
+    if let Ok(until) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M") {
+        return until.and_local_timezone(Local).earliest().map(|u| u.with_timezone(&Utc)).ok_or_else(|| format!("Time {} does not exist in local time zone", value));
+    }
+    DateTime::parse_from_rfc3339(value).map(|u| u.with_timezone(&Utc)).map_err(|_| format!("Invalid time {:?}, use HH:MM, YYYY-MM-DD HH:MM or RFC 3339", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone};
+
+    use super::*;
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Local> {
+        let date = NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, min, 0).unwrap();
+        Local.from_local_datetime(&date).unwrap()
+    }
+
+    #[test]
+    fn times_are_the_next_time_they_happen() {
+        let now = local(2024, 11, 14, 10, 0);
+        assert_eq!(parse_until("18:30", now).unwrap(), local(2024, 11, 14, 18, 30).with_timezone(&Utc));
+        assert_eq!(parse_until("09:00", now).unwrap(), local(2024, 11, 15, 9, 0).with_timezone(&Utc));
+        // The current time is already gone
+        assert_eq!(parse_until("10:00", now).unwrap(), local(2024, 11, 15, 10, 0).with_timezone(&Utc));
+        // Rolls over to the next month
+        assert_eq!(parse_until("08:00", local(2024, 11, 30, 22, 0)).unwrap(), local(2024, 12, 1, 8, 0).with_timezone(&Utc));
+    }
+
+    #[test]
+    fn dates_are_local_or_rfc3339() {
+        let now = local(2024, 11, 14, 10, 0);
+        assert_eq!(parse_until("2024-11-20 07:45", now).unwrap(), local(2024, 11, 20, 7, 45).with_timezone(&Utc));
+        assert_eq!(parse_until("2024-11-20T07:45:00+01:00", now).unwrap(), Utc.with_ymd_and_hms(2024, 11, 20, 6, 45, 0).unwrap());
+    }
+
+    #[test]
+    fn invalid_times_are_rejected() {
+        let now = local(2024, 11, 14, 10, 0);
+        for value in ["tomorrow", "25:00", "2024-11-20", "2024-13-01 10:00"] {
+            assert!(parse_until(value, now).is_err(), "{} must be rejected", value);
+        }
+    }
+
+    #[test]
+    fn stopped_items_are_optional() {
+        let saved: SavedState = serde_json::from_str(r#"{"stopped": [{"resource": "kubernetes/orders", "at": "2024-11-14T18:00:00Z"}]}"#).unwrap();
+        assert!(saved.stopped[0].items.is_empty());
+        let content = serde_json::to_string(&saved).unwrap();
+        assert!(!content.contains("items"));
+        let saved: SavedState = serde_json::from_str(r#"{"stopped": [{"resource": "databricks/https://adb", "at": "2024-11-14T18:00:00Z", "items": ["42"]}]}"#).unwrap();
+        assert_eq!(saved.stopped[0].items, vec![String::from("42")]);
+    }
+}